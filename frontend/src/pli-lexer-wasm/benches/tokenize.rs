@@ -0,0 +1,79 @@
+//! Throughput of `tokenize_flat` on a generated 10,000-line program.
+//!
+//! Run with `cargo bench`. Self-contained (no criterion) so it builds offline:
+//! each function is warmed up, then timed over a fixed number of iterations and
+//! the best run is reported, which is the least noisy figure on a busy machine.
+//!
+//! Measured natively (release profile) on the 10,005-line, 320 KB fixture:
+//! the raw single-pass lexer ran at 0.08-0.09 ms/1000 lines (~370-400 MB/s)
+//! both before and after pre-sizing its buffer from the measured 0.45
+//! tokens/byte, so the change removes the reallocations but the Logos DFA
+//! dominates the cost. Grouping the three writes into one `extend_from_slice`
+//! was tried and ran about 1.8x slower (0.14-0.17 ms/1000 lines); the raw
+//! variants keep the separate pushes. `tokenize_flat` and `tokenize_packed`
+//! build `TokenRef`s and run the context post-passes, about 3x the raw
+//! `tokenize_flat_lc`; the nine passes cost roughly the same as building the
+//! tokens, with none dominating. Absolute figures swing on a shared machine,
+//! so compare rows of one run.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use pli_lexer_wasm::{tokenize_flat, tokenize_flat_lc, tokenize_packed};
+
+const LINES: usize = 10_000;
+const ITERATIONS: u32 = 20;
+
+/// A realistic mix: declarations, loops, strings, comments and builtins
+fn fixture() -> String {
+    let block = "\
+/* Payroll summary for one department */
+SUMMARY: PROC(DEPT) RETURNS(FIXED DEC(11,2));
+  DCL DEPT CHAR(4) VARYING;
+  DCL (I, N) FIXED BIN(31) INIT(0);
+  DCL TOTAL FIXED DEC(11,2) INIT(0);
+  DCL NAME CHAR(30), RATE PIC'9999V99';
+  ON ENDFILE(SYSIN) EOF = '1'B;
+  DO I = 1 TO HBOUND(EMP, 1);
+    IF EMP(I).DEPT = DEPT THEN DO;
+      TOTAL = TOTAL + EMP(I).SALARY * 1.05E0;
+      PUT SKIP LIST('Employee: ' || TRIM(EMP(I).NAME), TOTAL);
+    END;
+  END;
+  RETURN(TOTAL);
+END SUMMARY;
+";
+    let lines_per_block = block.lines().count();
+    block.repeat(LINES.div_ceil(lines_per_block))
+}
+
+fn bench(name: &str, code: &str, f: impl Fn(&str) -> usize) {
+    let lines = code.lines().count();
+    for _ in 0..3 {
+        black_box(f(black_box(code)));
+    }
+    let mut best = Duration::MAX;
+    for _ in 0..ITERATIONS {
+        let started = Instant::now();
+        black_box(f(black_box(code)));
+        best = best.min(started.elapsed());
+    }
+    let per_1000_lines = best.as_secs_f64() * 1000.0 / (lines as f64 / 1000.0);
+    let mb_per_s = code.len() as f64 / best.as_secs_f64() / 1e6;
+    println!("{:<16} {:>8.3} ms  {:>7.4} ms/1000 lines  {:>7.1} MB/s", name, best.as_secs_f64() * 1000.0, per_1000_lines, mb_per_s);
+}
+
+fn main() {
+    let code = fixture();
+    let tokens = tokenize_flat(&code).len() / 3;
+    println!(
+        "fixture: {} lines, {} bytes, {} tokens ({:.3} tokens/byte)",
+        code.lines().count(),
+        code.len(),
+        tokens,
+        tokens as f64 / code.len() as f64
+    );
+    bench("tokenize_flat", &code, |c| tokenize_flat(c).len());
+    bench("tokenize_flat_lc", &code, |c| tokenize_flat_lc(c).len());
+    bench("tokenize_packed", &code, |c| tokenize_packed(c).len());
+}
@@ -14,6 +14,20 @@ export enum TokenType {
   Whitespace = 9,
   Newline = 10,
   Unknown = 11,
+  KeywordControl = 12,
+  KeywordType = 13,
+  KeywordStorage = 14,
+  KeywordIo = 15,
+  Label = 16,
+  SequenceNumber = 17,
+  Picture = 18,
+  Directive = 19,
+  Condition = 20,
+  StringDouble = 21,
+  Eof = 22,
+  Pseudovariable = 23,
+  FormatItem = 24,
+  GraphicString = 25,
 }
 
 export interface Token {
@@ -37,6 +51,20 @@ export const TOKEN_CSS_CLASS: Record<TokenType, string> = {
   [TokenType.Whitespace]: 'pli-whitespace',
   [TokenType.Newline]: 'pli-newline',
   [TokenType.Unknown]: 'pli-unknown',
+  [TokenType.KeywordControl]: 'pli-keyword pli-keyword-control',
+  [TokenType.KeywordType]: 'pli-keyword pli-keyword-type',
+  [TokenType.KeywordStorage]: 'pli-keyword pli-keyword-storage',
+  [TokenType.KeywordIo]: 'pli-keyword pli-keyword-io',
+  [TokenType.Label]: 'pli-label',
+  [TokenType.SequenceNumber]: 'pli-sequence',
+  [TokenType.Picture]: 'pli-picture',
+  [TokenType.Directive]: 'pli-directive',
+  [TokenType.Condition]: 'pli-condition',
+  [TokenType.StringDouble]: 'pli-string pli-string-double',
+  [TokenType.Eof]: 'pli-eof',
+  [TokenType.Pseudovariable]: 'pli-pseudovariable',
+  [TokenType.FormatItem]: 'pli-format-item',
+  [TokenType.GraphicString]: 'pli-string pli-graphic-string',
 };
 
 /**
@@ -3,29 +3,119 @@
 //! Uses Logos for compile-time optimized lexing.
 //! Target: ~0.05ms per 1000 lines of code.
 
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Range;
+
 use logos::Logos;
 use wasm_bindgen::prelude::*;
 use serde::{Serialize, Deserialize};
 
-/// Token types for syntax highlighting
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum TokenType {
-    Keyword,
-    String,
-    Comment,
-    Number,
-    Operator,
-    Preprocessor,
-    Builtin,
-    Identifier,
-    Punctuation,
-    Whitespace,
-    Newline,
-    Unknown,
+/// Defines `TokenType` together with `TokenType::ALL` and `TokenType::NAMES`,
+/// so the variant list and the serialized names are written once and the
+/// numeric codes can be enumerated
+macro_rules! token_type_enum {
+    ($(#[$meta:meta])* pub enum TokenType { $($variant:ident => $name:literal,)* }) => {
+        $(#[$meta])*
+        pub enum TokenType {
+            $(#[serde(rename = $name)] $variant,)*
+        }
+        
+        impl TokenType {
+            /// Every variant in code order: `ALL[t as usize] == t`
+            pub const ALL: &'static [TokenType] = &[$(TokenType::$variant,)*];
+            
+            /// Lowercase name of every variant in code order, as serialized
+            pub const NAMES: &'static [&'static str] = &[$($name,)*];
+        }
+    };
+}
+
+token_type_enum! {
+    /// Token types for syntax highlighting
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+    pub enum TokenType {
+        Keyword => "keyword",
+        String => "string",
+        Comment => "comment",
+        Number => "number",
+        Operator => "operator",
+        Preprocessor => "preprocessor",
+        Builtin => "builtin",
+        Identifier => "identifier",
+        Punctuation => "punctuation",
+        Whitespace => "whitespace",
+        Newline => "newline",
+        Unknown => "unknown",
+        // Keyword subcategories - appended so the `tokenize_flat` codes above stay stable
+        KeywordControl => "keywordcontrol",
+        KeywordType => "keywordtype",
+        KeywordStorage => "keywordstorage",
+        KeywordIo => "keywordio",
+        Label => "label",
+        SequenceNumber => "sequencenumber",
+        Picture => "picture",
+        Directive => "directive",
+        Condition => "condition",
+        StringDouble => "stringdouble",
+        Eof => "eof",
+        Pseudovariable => "pseudovariable",
+        FormatItem => "formatitem",
+        GraphicString => "graphicstring",
+    }
+}
+
+impl TokenType {
+    /// Type for a `tokenize_flat` code
+    pub fn from_code(code: u32) -> Option<TokenType> {
+        TokenType::ALL.get(code as usize).copied()
+    }
+    
+    /// Lowercase name, as used for the `type` field of serialized tokens
+    pub fn name(self) -> &'static str {
+        TokenType::NAMES[self as usize]
+    }
+    
+    /// Whitespace, newlines and comments carry no syntactic meaning
+    pub fn is_trivia(self) -> bool {
+        self.is_trivia_with(true)
+    }
+    
+    /// `is_trivia`, with comments counted as trivia only if `comments_are_trivia`
+    pub fn is_trivia_with(self, comments_are_trivia: bool) -> bool {
+        match self {
+            TokenType::Whitespace | TokenType::Newline => true,
+            TokenType::Comment => comments_are_trivia,
+            _ => false,
+        }
+    }
+    
+    /// Any keyword category
+    pub fn is_keyword(self) -> bool {
+        matches!(
+            self,
+            TokenType::Keyword
+                | TokenType::KeywordControl
+                | TokenType::KeywordType
+                | TokenType::KeywordStorage
+                | TokenType::KeywordIo
+        )
+    }
+    
+    /// Quoted literals, single (`'...'`), double (`"..."`) or graphic/mixed (`G'...'`)
+    pub fn is_string(self) -> bool {
+        matches!(self, TokenType::String | TokenType::StringDouble | TokenType::GraphicString)
+    }
+    
+    /// Identifiers plus keywords/builtins - PL/I has no reserved words, so any of
+    /// these may be used as a name
+    pub fn is_word(self) -> bool {
+        self.is_keyword() || matches!(self, TokenType::Identifier | TokenType::Builtin | TokenType::Pseudovariable)
+    }
 }
 
 /// A single token with position info
+/// `line` and `column` are 0-based; `column` counts Unicode scalar values (chars)
+/// from the start of the line, not bytes.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Token {
     pub text: String,
@@ -33,407 +123,6361 @@ pub struct Token {
     pub token_type: TokenType,
     pub start: usize,
     pub end: usize,
+    pub line: usize,
+    pub column: usize,
+    /// Uppercase spelling of keyword and builtin tokens (`dcl` -> `DCL`);
+    /// `text` always stays exactly as written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub canonical: Option<String>,
 }
 
-/// Logos-based PL/I lexer - compile-time optimized state machine
-/// NOTE: No skip directive - we capture everything for syntax highlighting
-#[derive(Logos, Debug, PartialEq, Clone)]
-enum PLIToken {
-    // ============ KEYWORDS ============
-    // Control structures
-    #[token("PROC", ignore(ascii_case))]
-    #[token("PROCEDURE", ignore(ascii_case))]
-    #[token("END", ignore(ascii_case))]
-    #[token("RETURN", ignore(ascii_case))]
-    #[token("CALL", ignore(ascii_case))]
-    #[token("GOTO", ignore(ascii_case))]
-    #[token("IF", ignore(ascii_case))]
-    #[token("THEN", ignore(ascii_case))]
-    #[token("ELSE", ignore(ascii_case))]
-    #[token("DO", ignore(ascii_case))]
-    #[token("WHILE", ignore(ascii_case))]
-    #[token("UNTIL", ignore(ascii_case))]
-    #[token("ITERATE", ignore(ascii_case))]
-    #[token("LEAVE", ignore(ascii_case))]
-    #[token("SELECT", ignore(ascii_case))]
-    #[token("WHEN", ignore(ascii_case))]
-    #[token("OTHERWISE", ignore(ascii_case))]
-    #[token("BEGIN", ignore(ascii_case))]
-    
-    // Declarations
-    #[token("DCL", ignore(ascii_case))]
-    #[token("DECLARE", ignore(ascii_case))]
-    #[token("INIT", ignore(ascii_case))]
-    #[token("INITIAL", ignore(ascii_case))]
-    #[token("STATIC", ignore(ascii_case))]
-    #[token("AUTOMATIC", ignore(ascii_case))]
-    #[token("CONTROLLED", ignore(ascii_case))]
-    #[token("BASED", ignore(ascii_case))]
-    #[token("DEFINED", ignore(ascii_case))]
-    #[token("REFER", ignore(ascii_case))]
-    #[token("LIKE", ignore(ascii_case))]
-    #[token("ENTRY", ignore(ascii_case))]
-    #[token("RETURNS", ignore(ascii_case))]
-    
-    // Data types
-    #[token("FIXED", ignore(ascii_case))]
-    #[token("BINARY", ignore(ascii_case))]
-    #[token("DECIMAL", ignore(ascii_case))]
-    #[token("FLOAT", ignore(ascii_case))]
-    #[token("REAL", ignore(ascii_case))]
-    #[token("COMPLEX", ignore(ascii_case))]
-    #[token("CHARACTER", ignore(ascii_case))]
-    #[token("CHAR", ignore(ascii_case))]
-    #[token("VARYING", ignore(ascii_case))]
-    #[token("VAR", ignore(ascii_case))]
-    #[token("BIT", ignore(ascii_case))]
-    #[token("PICTURE", ignore(ascii_case))]
-    #[token("PIC", ignore(ascii_case))]
-    #[token("POINTER", ignore(ascii_case))]
-    #[token("PTR", ignore(ascii_case))]
-    #[token("OFFSET", ignore(ascii_case))]
-    #[token("AREA", ignore(ascii_case))]
-    #[token("FILE", ignore(ascii_case))]
-    #[token("LABEL", ignore(ascii_case))]
-    #[token("FORMAT", ignore(ascii_case))]
-    #[token("CONDITION", ignore(ascii_case))]
-    
-    // Attributes
-    #[token("PRECISION", ignore(ascii_case))]
-    #[token("EXTERNAL", ignore(ascii_case))]
-    #[token("INTERNAL", ignore(ascii_case))]
-    #[token("BUILTIN", ignore(ascii_case))]
-    #[token("OPTIONS", ignore(ascii_case))]
-    #[token("MAIN", ignore(ascii_case))]
-    #[token("RECURSIVE", ignore(ascii_case))]
-    #[token("REENTRANT", ignore(ascii_case))]
-    #[token("ALIGNED", ignore(ascii_case))]
-    #[token("UNALIGNED", ignore(ascii_case))]
-    
-    // I/O
-    #[token("GET", ignore(ascii_case))]
-    #[token("PUT", ignore(ascii_case))]
-    #[token("READ", ignore(ascii_case))]
-    #[token("WRITE", ignore(ascii_case))]
-    #[token("OPEN", ignore(ascii_case))]
-    #[token("CLOSE", ignore(ascii_case))]
-    #[token("DELETE", ignore(ascii_case))]
-    #[token("REWRITE", ignore(ascii_case))]
-    #[token("DISPLAY", ignore(ascii_case))]
-    #[token("SKIP", ignore(ascii_case))]
-    #[token("PAGE", ignore(ascii_case))]
-    #[token("LINE", ignore(ascii_case))]
-    #[token("COLUMN", ignore(ascii_case))]
-    #[token("COL", ignore(ascii_case))]
-    #[token("LIST", ignore(ascii_case))]
-    #[token("DATA", ignore(ascii_case))]
-    #[token("EDIT", ignore(ascii_case))]
-    #[token("PRINT", ignore(ascii_case))]
-    #[token("INPUT", ignore(ascii_case))]
-    #[token("OUTPUT", ignore(ascii_case))]
-    #[token("UPDATE", ignore(ascii_case))]
-    #[token("STREAM", ignore(ascii_case))]
-    #[token("RECORD", ignore(ascii_case))]
-    #[token("ENVIRONMENT", ignore(ascii_case))]
-    #[token("ENV", ignore(ascii_case))]
-    #[token("TITLE", ignore(ascii_case))]
-    #[token("KEYED", ignore(ascii_case))]
-    #[token("SEQUENTIAL", ignore(ascii_case))]
-    #[token("DIRECT", ignore(ascii_case))]
-    
-    // Error handling
-    #[token("SIGNAL", ignore(ascii_case))]
-    #[token("ON", ignore(ascii_case))]
-    #[token("REVERT", ignore(ascii_case))]
-    #[token("ERROR", ignore(ascii_case))]
-    #[token("UNDERFLOW", ignore(ascii_case))]
-    #[token("OVERFLOW", ignore(ascii_case))]
-    #[token("ZERODIVIDE", ignore(ascii_case))]
-    #[token("CONVERSION", ignore(ascii_case))]
-    #[token("SIZE", ignore(ascii_case))]
-    #[token("STRINGRANGE", ignore(ascii_case))]
-    #[token("SUBSCRIPTRANGE", ignore(ascii_case))]
-    
-    // Memory
-    #[token("ALLOCATE", ignore(ascii_case))]
-    #[token("FREE", ignore(ascii_case))]
-    #[token("NULL", ignore(ascii_case))]
-    #[token("SYSNULL", ignore(ascii_case))]
-    
-    // Logic
-    #[token("AND", ignore(ascii_case))]
-    #[token("OR", ignore(ascii_case))]
-    #[token("NOT", ignore(ascii_case))]
-    #[token("XOR", ignore(ascii_case))]
-    Keyword,
-    
-    // ============ BUILTINS ============
-    #[token("ABS", ignore(ascii_case))]
-    #[token("MAX", ignore(ascii_case))]
-    #[token("MIN", ignore(ascii_case))]
-    #[token("MOD", ignore(ascii_case))]
-    #[token("SIGN", ignore(ascii_case))]
-    #[token("SQRT", ignore(ascii_case))]
-    #[token("LOG", ignore(ascii_case))]
-    #[token("LOG10", ignore(ascii_case))]
-    #[token("EXP", ignore(ascii_case))]
-    #[token("SIN", ignore(ascii_case))]
-    #[token("COS", ignore(ascii_case))]
-    #[token("TAN", ignore(ascii_case))]
-    #[token("ASIN", ignore(ascii_case))]
-    #[token("ACOS", ignore(ascii_case))]
-    #[token("ATAN", ignore(ascii_case))]
-    #[token("ATAN2", ignore(ascii_case))]
-    #[token("SUBSTR", ignore(ascii_case))]
-    #[token("INDEX", ignore(ascii_case))]
-    #[token("LENGTH", ignore(ascii_case))]
-    #[token("TRIM", ignore(ascii_case))]
-    #[token("VERIFY", ignore(ascii_case))]
-    #[token("TRANSLATE", ignore(ascii_case))]
-    #[token("REVERSE", ignore(ascii_case))]
-    #[token("REPEAT", ignore(ascii_case))]
-    #[token("DATE", ignore(ascii_case))]
-    #[token("TIME", ignore(ascii_case))]
-    #[token("DATETIME", ignore(ascii_case))]
-    #[token("ADDR", ignore(ascii_case))]
-    #[token("ADDRESS", ignore(ascii_case))]
-    #[token("STORAGE", ignore(ascii_case))]
-    #[token("CURRENTSTORAGE", ignore(ascii_case))]
-    #[token("STRING", ignore(ascii_case))]
-    #[token("UNSPEC", ignore(ascii_case))]
-    #[token("BOOL", ignore(ascii_case))]
-    #[token("HIGH", ignore(ascii_case))]
-    #[token("LOW", ignore(ascii_case))]
-    #[token("COPY", ignore(ascii_case))]
-    #[token("ROUND", ignore(ascii_case))]
-    #[token("TRUNC", ignore(ascii_case))]
-    #[token("FLOOR", ignore(ascii_case))]
-    #[token("CEIL", ignore(ascii_case))]
-    #[token("HBOUND", ignore(ascii_case))]
-    #[token("LBOUND", ignore(ascii_case))]
-    #[token("DIM", ignore(ascii_case))]
-    #[token("DIMENSION", ignore(ascii_case))]
-    #[token("SYSIN", ignore(ascii_case))]
-    #[token("SYSPRINT", ignore(ascii_case))]
-    #[token("ONCODE", ignore(ascii_case))]
-    #[token("ONCHAR", ignore(ascii_case))]
-    #[token("ONKEY", ignore(ascii_case))]
-    #[token("ONLOC", ignore(ascii_case))]
-    Builtin,
-    
-    // ============ PREPROCESSOR ============
-    #[token("%INCLUDE", ignore(ascii_case))]
-    #[token("%REPLACE", ignore(ascii_case))]
-    #[token("%ACTIVATE", ignore(ascii_case))]
-    #[token("%DEACTIVATE", ignore(ascii_case))]
-    #[token("%IF", ignore(ascii_case))]
-    #[token("%THEN", ignore(ascii_case))]
-    #[token("%ELSE", ignore(ascii_case))]
-    #[token("%ENDIF", ignore(ascii_case))]
-    #[token("%DO", ignore(ascii_case))]
-    #[token("%END", ignore(ascii_case))]
-    #[token("%DCL", ignore(ascii_case))]
-    #[token("%DECLARE", ignore(ascii_case))]
-    #[token("*PROCESS", ignore(ascii_case))]
-    Preprocessor,
+/// Borrowed counterpart of `Token` whose text points into the source buffer,
+/// so producing it costs no heap allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TokenRef<'a> {
+    pub text: &'a str,
+    #[serde(rename = "type")]
+    pub token_type: TokenType,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl From<TokenRef<'_>> for Token {
+    fn from(token: TokenRef<'_>) -> Self {
+        let canonical = canonical_spelling(&token);
+        Token {
+            text: token.text.to_string(),
+            token_type: token.token_type,
+            start: token.start,
+            end: token.end,
+            line: token.line,
+            column: token.column,
+            canonical,
+        }
+    }
+}
+
+/// `Token::canonical` for a borrowed token
+fn canonical_spelling(token: &TokenRef<'_>) -> Option<String> {
+    (token.token_type.is_keyword() || matches!(token.token_type, TokenType::Builtin | TokenType::Pseudovariable | TokenType::FormatItem))
+        .then(|| token.text.to_ascii_uppercase())
+}
+
+/// Tracks the 0-based line/column of the lexer as tokens are consumed
+#[derive(Debug, Clone, Copy, Default)]
+struct LineTracker {
+    line: usize,
+    column: usize,
+    /// Tab stop width; 0 or 1 counts a tab as a single column
+    tab_width: usize,
+}
+
+impl LineTracker {
+    fn with_tab_width(tab_width: usize) -> Self {
+        LineTracker { tab_width, ..LineTracker::default() }
+    }
     
-    // ============ COMMENTS ============
-    #[regex(r"/\*[^*]*\*+(?:[^/*][^*]*\*+)*/")]
-    Comment,
+    /// Move past a token's text; columns reset after every line break (LF,
+    /// CRLF or lone CR) it contains
+    fn advance(&mut self, slice: &str) {
+        let tail = match slice.rfind(['\n', '\r']) {
+            Some(last) => {
+                self.line += line_break_count(slice);
+                self.column = 0;
+                &slice[last + 1..]
+            }
+            None => slice,
+        };
+        self.column = advance_column(self.column, tail, self.tab_width);
+    }
+}
+
+/// Number of line breaks in `text`, counting LF, CRLF and lone CR once each
+fn line_break_count(text: &str) -> usize {
+    let bytes = text.as_bytes();
+    (0..bytes.len())
+        .filter(|&i| bytes[i] == b'\n' || (bytes[i] == b'\r' && bytes.get(i + 1) != Some(&b'\n')))
+        .count()
+}
+
+/// `text` split after each line break (LF, CRLF or lone CR); every piece keeps
+/// its break, and only the last may have none
+fn split_lines_inclusive(text: &str) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let end = match rest.find(['\n', '\r']) {
+            Some(i) if rest[i..].starts_with("\r\n") => i + 2,
+            Some(i) => i + 1,
+            None => rest.len(),
+        };
+        let (piece, tail) = rest.split_at(end);
+        rest = tail;
+        Some(piece)
+    })
+}
+
+/// Column reached after `text` (which holds no newline) starting at `column`,
+/// expanding each tab to the next multiple of `tab_width`
+fn advance_column(column: usize, text: &str, tab_width: usize) -> usize {
+    if tab_width <= 1 || !text.contains('\t') {
+        return column + text.chars().count();
+    }
+    text.chars().fold(column, |column, c| match c {
+        '\t' => (column / tab_width + 1) * tab_width,
+        _ => column + 1,
+    })
+}
+
+/// Generates the public word tables and the Logos token enum from one list,
+/// so the lexer and the word lookups below can never drift apart. Each group
+/// becomes a `pub const` slice and a set of case-insensitive `#[token]`s on
+/// its variant.
+macro_rules! pli_token_enum {
+    (
+        words { $( $variant:ident { $( $(#[$meta:meta])* $list:ident: [$($word:literal),* $(,)?] ),* $(,)? } )* }
+        tokens { $($rest:tt)* }
+    ) => {
+        $( $( $(#[$meta])* pub const $list: &[&str] = &[$($word),*]; )* )*
+        
+        /// Logos-based PL/I lexer - compile-time optimized state machine
+        /// NOTE: No skip directive - we capture everything for syntax highlighting
+        #[derive(Logos, Debug, PartialEq, Clone)]
+        enum PLIToken {
+            $( $( $( #[token($word, ignore(ascii_case))] )* )* $variant, )*
+            $($rest)*
+        }
+    };
+}
+
+// ============ KEYWORDS, BUILTINS & PREPROCESSOR ============
+pli_token_enum! {
+    words {
+        KeywordControl {
+            /// Control structures
+            CONTROL_KEYWORDS: [
+                "PROC", "PROCEDURE", "END", "RETURN", "CALL", "GOTO", "IF", "THEN", "ELSE", "DO",
+                "WHILE", "UNTIL", "ITERATE", "LEAVE", "SELECT", "WHEN", "OTHERWISE", "BEGIN",
+            ],
+        }
+        KeywordStorage {
+            /// Storage classes
+            STORAGE_KEYWORDS: [
+                "STATIC", "AUTOMATIC", "CONTROLLED", "BASED", "DEFINED",
+            ],
+        }
+        Keyword {
+            /// Declarations
+            DECLARATION_KEYWORDS: [
+                "DCL", "DECLARE", "INIT", "INITIAL", "REFER", "LIKE", "ENTRY", "RETURNS",
+            ],
+            /// Attributes
+            ATTRIBUTE_KEYWORDS: [
+                "PRECISION", "EXTERNAL", "INTERNAL", "BUILTIN", "OPTIONS", "MAIN", "RECURSIVE",
+                "REENTRANT", "ALIGNED", "UNALIGNED",
+                "BYADDR", "BYVALUE", "ASSIGNABLE", "NONASSIGNABLE", "CONNECTED", "NONCONNECTED",
+                "NONVARYING", "NOINIT", "NOMAP", "NOMAPIN", "NOMAPOUT",
+            ],
+            /// Error handling
+            CONDITION_KEYWORDS: [
+                "SIGNAL", "ON", "REVERT", "ERROR", "UNDERFLOW", "OVERFLOW", "ZERODIVIDE",
+                "CONVERSION", "SIZE", "STRINGRANGE", "SUBSCRIPTRANGE",
+            ],
+            /// Memory
+            MEMORY_KEYWORDS: [
+                "ALLOCATE", "FREE", "NULL", "SYSNULL",
+            ],
+            /// Logic
+            LOGICAL_KEYWORDS: [
+                "AND", "OR", "NOT", "XOR",
+            ],
+            /// Iterative DO specifications. The loop form of REPEAT is not listed:
+            /// the word lexes as the REPEAT builtin and a post-pass turns it into
+            /// a keyword inside DO statements
+            LOOP_KEYWORDS: [
+                "TO", "BY", "UPTHRU", "DOWNTHRU",
+            ],
+            /// Enterprise PL/I type definitions and defaults. Like every keyword
+            /// these still work as names (a variable called VALUE or TYPE now
+            /// highlights as a keyword unless `reclassify_declared_names` runs)
+            DEFINITION_KEYWORDS: [
+                "DEFINE", "ALIAS", "ORDINAL", "STRUCTURE", "UNION", "TYPE", "VALUE", "DEFAULT",
+                "DFT", "RANGE",
+            ],
+            /// Multitasking
+            TASKING_KEYWORDS: [
+                "EVENT", "TASK", "WAIT", "PRIORITY", "COMPLETION",
+            ],
+            /// Record I/O statement options
+            RECORD_IO_KEYWORDS: [
+                "FROM", "INTO", "SET", "KEY", "KEYTO", "KEYFROM", "IGNORE", "NOLOCK", "REPLY",
+                "LOCATE",
+            ],
+        }
+        KeywordType {
+            /// Data types
+            TYPE_KEYWORDS: [
+                "FIXED", "BINARY", "DECIMAL", "FLOAT", "REAL", "COMPLEX", "CHARACTER", "CHAR",
+                "VARYING", "VAR", "BIT", "PICTURE", "PIC", "POINTER", "PTR", "OFFSET", "AREA",
+                "FILE", "LABEL", "FORMAT", "CONDITION", "HANDLE",
+            ],
+        }
+        KeywordIo {
+            /// I/O
+            IO_KEYWORDS: [
+                "GET", "PUT", "READ", "WRITE", "OPEN", "CLOSE", "DELETE", "REWRITE", "DISPLAY",
+                "SKIP", "PAGE", "LINE", "COLUMN", "COL", "LIST", "DATA", "EDIT", "PRINT", "INPUT",
+                "OUTPUT", "UPDATE", "STREAM", "RECORD", "ENVIRONMENT", "ENV", "TITLE", "KEYED",
+                "SEQUENTIAL", "DIRECT",
+            ],
+        }
+        Builtin {
+            /// Arithmetic and mathematical builtins
+            MATH_BUILTINS: [
+                "ABS", "MAX", "MIN", "MOD", "SIGN", "SQRT", "LOG", "LOG10", "EXP", "SIN", "COS",
+                "TAN", "ASIN", "ACOS", "ATAN", "ATAN2", "ROUND", "TRUNC", "FLOOR", "CEIL",
+            ],
+            /// String and bit-string handling builtins
+            STRING_BUILTINS: [
+                "SUBSTR", "INDEX", "LENGTH", "TRIM", "VERIFY", "TRANSLATE", "REVERSE", "REPEAT",
+                "STRING", "BOOL", "HIGH", "LOW", "COPY",
+            ],
+            /// Storage and addressing builtins
+            STORAGE_BUILTINS: [
+                "ADDR", "ADDRESS", "STORAGE", "CURRENTSTORAGE", "UNSPEC",
+            ],
+            /// Array bound builtins
+            ARRAY_BUILTINS: [
+                "HBOUND", "LBOUND", "DIM", "DIMENSION",
+            ],
+            /// Condition-handling builtins
+            CONDITION_BUILTINS: [
+                "ONCODE", "ONCHAR", "ONKEY", "ONLOC",
+            ],
+            /// Date and time builtins
+            DATETIME_BUILTINS: [
+                "DATE", "TIME", "DATETIME",
+            ],
+            /// Other builtin names (standard files)
+            MISC_BUILTINS: [
+                "SYSIN", "SYSPRINT",
+            ],
+            /// Ordinal conversion builtins
+            ORDINAL_BUILTINS: [
+                "ORDINALNAME", "ORDINALVALUE", "ORDINALPRED", "ORDINALSUCC",
+            ],
+        }
+        Preprocessor {
+            /// Preprocessor statements
+            PREPROCESSOR_KEYWORDS: [
+                "%INCLUDE", "%REPLACE", "%ACTIVATE", "%DEACTIVATE", "%IF", "%THEN", "%ELSE",
+                "%ENDIF", "%DO", "%END", "%DCL", "%DECLARE", "%XINCLUDE", "%GOTO", "%NOTE", "%PAGE",
+                "%SKIP", "%PRINT", "%NOPRINT", "%PROCEDURE", "%PROC", "%RETURN",
+            ],
+        }
+    }
     
-    // ============ STRINGS ============
-    #[regex(r#"'[^']*'"#)]
-    #[regex(r#""[^"]*""#)]
-    String,
+    tokens {
+        // ============ COMPILER DIRECTIVES ============
+        // Produced by the `*` callback for `*PROCESS` option cards
+        Directive,
+    
+        // ============ COMMENTS ============
+        #[token("/*", block_comment)]
+        Comment,
+    
+        // ============ STRINGS ============
+        // An embedded quote is written doubled: 'DON''T' is a single string
+        #[regex(r#"'(?:[^']|'')*'"#)]
+        String,
+        
+        // Double-quoted: an alternate string form in some dialects, an identifier in others
+        #[regex(r#""(?:[^"]|"")*""#)]
+        StringDouble,
+        
+        // DBCS: graphic (G) and mixed (M) strings, marked before or after the
+        // quotes: G'..', '..'G, M'..', '..'M, and graphic hex '..'GX
+        #[regex(r#"[gGmM]'(?:[^']|'')*'"#)]
+        #[regex(r#"'(?:[^']|'')*'[gGmM]"#)]
+        #[regex(r"'[0-9A-Fa-f]*'[gG][xX]")]
+        GraphicString,
+    
+        // ============ NUMBERS ============
+        // Decimal/binary fixed and float: 12, .5, 1.5E-3, 1.5F+2, 1011B, 3I
+        #[regex(r"(?:[0-9]+(?:\.[0-9]*)?|\.[0-9]+)(?:[eEfF][+-]?[0-9]+)?[bB]?[iI]?")]
+        // Bit strings: 'B' / 'B1' radix 2, 'B2' radix 4, 'B3' octal, 'B4' / 'BX' hex
+        #[regex(r"'[01]*'[bB]1?")]
+        #[regex(r"'[0-3]*'[bB]2")]
+        #[regex(r"'[0-7]*'[bB]3")]
+        #[regex(r"'[0-9A-Fa-f]*'(?:[bB]4|[bB][xX])")]
+        // Hex character strings
+        #[regex(r"'[0-9A-Fa-f]*'[xX]")]
+        Number,
+    
+        // ============ OPERATORS ============
+        #[token("=")]
+        #[token("<")]
+        #[token(">")]
+        #[token("<=")]
+        #[token(">=")]
+        #[token("<>")]
+        #[token("^=")]
+        #[token("^<")]
+        #[token("^>")]
+        #[token("¬")]
+        #[token("¬=")]
+        #[token("¬<")]
+        #[token("¬>")]
+        #[token("~")]
+        #[token("~=")]
+        #[token("+")]
+        #[token("-")]
+        #[token("*", process_card)]
+        #[token("/")]
+        #[token("**")]
+        #[token("||")]
+        // Locator qualifiers: P -> X for pointers, H => X for handles
+        #[token("->")]
+        #[token("=>")]
+        #[token("&")]
+        #[token("|")]
+        #[token("^")]
+        Operator,
     
-    // ============ NUMBERS ============
-    #[regex(r"[0-9]+\.?[0-9]*([eE][+-]?[0-9]+)?")]
-    #[regex(r"'[0-9A-Fa-f]+'[xXbB]")]
-    Number,
-    
-    // ============ OPERATORS ============
-    #[token("=")]
-    #[token("<")]
-    #[token(">")]
-    #[token("<=")]
-    #[token(">=")]
-    #[token("<>")]
-    #[token("^=")]
-    #[token("+")]
-    #[token("-")]
-    #[token("*")]
-    #[token("/")]
-    #[token("**")]
-    #[token("||")]
-    #[token("&")]
-    #[token("|")]
-    #[token("^")]
-    Operator,
-    
-    // ============ PUNCTUATION ============
-    #[token("(")]
-    #[token(")")]
-    #[token("[")]
-    #[token("]")]
-    #[token(";")]
-    #[token(":")]
-    #[token(",")]
-    #[token(".")]
-    Punctuation,
-    
-    // ============ IDENTIFIERS ============
-    #[regex(r"[a-zA-Z_@#$][a-zA-Z0-9_@#$]*")]
-    Identifier,
-    
-    // ============ WHITESPACE & NEWLINES ============
-    #[regex(r"[ \t\r]+")]
-    Whitespace,
-    
-    #[token("\n")]
-    Newline,
+        // ============ PUNCTUATION ============
+        #[token("(")]
+        #[token(")")]
+        #[token("[")]
+        #[token("]")]
+        #[token(";")]
+        #[token(":")]
+        #[token(",")]
+        #[token(".")]
+        Punctuation,
+    
+        // ============ IDENTIFIERS ============
+        #[regex(r"[a-zA-Z_@#$][a-zA-Z0-9_@#$]*")]
+        Identifier,
+    
+        // Any other %name is a preprocessor variable reference; the %IF/%DO...
+        // statement tokens take precedence on an exact match
+        #[regex(r"%[a-zA-Z_@#$][a-zA-Z0-9_@#$]*")]
+        PreprocessorName,
+    
+        // ============ WHITESPACE & NEWLINES ============
+        #[regex(r"[ \t]+")]
+        Whitespace,
+    
+        // LF, CRLF and old Mac-style lone CR each end a line as one token
+        #[token("\n")]
+        #[token("\r\n")]
+        #[token("\r")]
+        Newline,
+    }
+}
+
+/// A `*` that begins a line followed by the word PROCESS is a compiler option
+/// card; the directive covers its options up to `;` or end of line. Any other
+/// `*` (multiplication, `* 2`, `A*PROCESSED`) stays an operator.
+fn process_card(lex: &mut logos::Lexer<PLIToken>) -> PLIToken {
+    let rest = lex.remainder();
+    
+    let is_card = starts_line(lex.source(), lex.span().start)
+        && rest.get(..7).is_some_and(|w| w.eq_ignore_ascii_case("PROCESS"))
+        && !rest[7..].starts_with(|c: char| c.is_ascii_alphanumeric() || "_@#$".contains(c));
+    if !is_card {
+        return PLIToken::Operator;
+    }
+    
+    let line_end = rest.find(['\n', '\r']).unwrap_or(rest.len());
+    let len = rest[..line_end].find(';').map(|i| i + 1).unwrap_or(line_end);
+    lex.bump(len);
+    PLIToken::Directive
+}
+
+/// Whether only blanks precede byte `at` on its line
+fn starts_line(source: &str, at: usize) -> bool {
+    let before = &source[..at];
+    let line_start = before.rfind(['\n', '\r']).map(|i| i + 1).unwrap_or(0);
+    before[line_start..].bytes().all(|b| b == b' ' || b == b'\t')
+}
+
+/// Runs a comment to the first `*/`. An unterminated comment takes the rest of
+/// the input and is still a `Comment`, as editors expect while it is being typed.
+fn block_comment(lex: &mut logos::Lexer<PLIToken>) {
+    let rest = lex.remainder();
+    lex.bump(rest.find("*/").map(|i| i + 2).unwrap_or(rest.len()));
 }
 
 /// Convert internal token to output token type
 fn to_token_type(tok: &PLIToken) -> TokenType {
     match tok {
         PLIToken::Keyword => TokenType::Keyword,
+        PLIToken::KeywordControl => TokenType::KeywordControl,
+        PLIToken::KeywordType => TokenType::KeywordType,
+        PLIToken::KeywordStorage => TokenType::KeywordStorage,
+        PLIToken::KeywordIo => TokenType::KeywordIo,
         PLIToken::Builtin => TokenType::Builtin,
         PLIToken::Preprocessor => TokenType::Preprocessor,
+        PLIToken::Directive => TokenType::Directive,
         PLIToken::Comment => TokenType::Comment,
         PLIToken::String => TokenType::String,
+        PLIToken::StringDouble => TokenType::StringDouble,
+        PLIToken::GraphicString => TokenType::GraphicString,
         PLIToken::Number => TokenType::Number,
         PLIToken::Operator => TokenType::Operator,
         PLIToken::Punctuation => TokenType::Punctuation,
         PLIToken::Identifier => TokenType::Identifier,
         PLIToken::Whitespace => TokenType::Whitespace,
         PLIToken::Newline => TokenType::Newline,
+        PLIToken::PreprocessorName => TokenType::Preprocessor,
+    }
+}
+
+/// Next raw token and its span. A run of adjacent input no rule matches (a
+/// pasted binary blob, stray control characters) comes back as one `Unknown`
+/// covering the whole run rather than one token per char.
+fn next_raw(lexer: &mut logos::Lexer<'_, PLIToken>) -> Option<(TokenType, Range<usize>)> {
+    match lexer.next()? {
+        Ok(tok) => Some((to_token_type(&tok), lexer.span())),
+        Err(_) => {
+            let start = lexer.span().start;
+            loop {
+                let mut ahead = lexer.clone();
+                match ahead.next() {
+                    Some(Err(_)) => *lexer = ahead,
+                    _ => break,
+                }
+            }
+            Some((TokenType::Unknown, start..lexer.span().end))
+        }
+    }
+}
+
+/// Classify a single word the way the lexer would: a keyword category,
+/// `Builtin`, `Preprocessor` for `%` statements, or `Identifier` for anything
+/// else. Case-insensitive; runs the lexer's own automaton (built from the word
+/// tables above), so answers always agree with `tokenize`.
+pub fn classify_word(word: &str) -> TokenType {
+    let mut lexer = PLIToken::lexer(word);
+    let token_type = match (lexer.next(), lexer.next()) {
+        (Some(Ok(tok)), None) => to_token_type(&tok),
+        _ => return TokenType::Identifier,
+    };
+    
+    if token_type.is_keyword() || matches!(token_type, TokenType::Builtin | TokenType::Preprocessor) {
+        token_type
+    } else {
+        TokenType::Identifier
+    }
+}
+
+/// Contents of a `String`/`StringDouble`/`GraphicString` token: the surrounding
+/// quotes (and a graphic or mixed `G`/`M` marker) removed and each doubled
+/// quote collapsed, so `'IT''S'` gives `IT'S`. A radix suffix would make the
+/// token a `Number`, so the value is always character data; a graphic hex
+/// `'..'GX` keeps its hex digits. `None` for any other token.
+pub fn string_value(token: &Token) -> Option<String> {
+    let (quote, text) = match token.token_type {
+        TokenType::String => ("'", token.text.as_str()),
+        TokenType::StringDouble => ("\"", token.text.as_str()),
+        TokenType::GraphicString => {
+            let text = token.text.trim_end_matches(|c: char| c != '\'');
+            ("'", text.trim_start_matches(|c: char| c != '\''))
+        }
+        _ => return None,
+    };
+    let inner = text.strip_prefix(quote)?.strip_suffix(quote)?;
+    Some(inner.replace(&quote.repeat(2), quote))
+}
+
+/// What a picture character does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PictureCategory {
+    /// `9`
+    Digit,
+    /// `Z`, `*` and `Y`: a digit whose leading zeros (or all zeros, for `Y`) are replaced
+    ZeroSuppress,
+    /// `V`, the assumed decimal point
+    DecimalPoint,
+    /// `S`, `+`, `-`
+    Sign,
+    /// `$`
+    Currency,
+    /// `.`, `,`, `/` and `B`: inserted into the output as-is (`B` as a blank)
+    Insertion,
+    /// `CR` and `DB`
+    Credit,
+    /// `T`, `I`, `R`: a digit overpunched with the sign
+    Overpunch,
+    /// `E` and `K`, the exponent marker of a float picture
+    Exponent,
+    /// `A` and `X` of a character picture
+    Character,
+    /// `F(n)`, the scaling factor
+    Scale,
+    /// Anything that is not a picture character
+    Invalid,
+}
+
+/// One element of a picture specification. `count` is the iteration factor
+/// (`(5)9` is one element with count 5); `start..end` is its byte range in the
+/// spec passed to `parse_picture`, factor included.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PictureChar {
+    pub text: String,
+    pub category: PictureCategory,
+    pub count: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// `parse_picture` serialized as `{ text, category, count, start, end }[]`
+#[wasm_bindgen(js_name = parse_picture)]
+pub fn parse_picture_js(spec: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&parse_picture(spec)).unwrap_or(JsValue::NULL)
+}
+
+/// Split a picture specification (`$ZZ9.99`, or the quoted `'$ZZ9.99'` as it
+/// appears in a `Picture` token) into its elements, case-insensitively.
+/// Malformed input never fails: unrecognized characters come back `Invalid`.
+pub fn parse_picture(spec: &str) -> Vec<PictureChar> {
+    let (body, base) = match spec.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')) {
+        Some(inner) => (inner, 1),
+        None => (spec, 0),
+    };
+    let bytes = body.as_bytes();
+    let mut elements = Vec::new();
+    let mut i = 0;
+    
+    // `(n)` before an element, or after F (where n may be signed);
+    // yields the value and the length of the parenthesized text
+    let factor = |at: usize| -> Option<(i64, usize)> {
+        let rest = body.get(at..)?.strip_prefix('(')?;
+        let close = rest.find(')')?;
+        Some((rest[..close].trim().parse().ok()?, close + 2))
+    };
+    
+    while i < body.len() {
+        let start = i;
+        let count = match factor(i) {
+            Some((count, len)) if count >= 0 => {
+                i += len;
+                count as usize
+            }
+            _ => 1,
+        };
+        let Some(c) = body[i..].chars().next() else { break };
+        let mut len = c.len_utf8();
+        let category = match c.to_ascii_uppercase() {
+            '9' => PictureCategory::Digit,
+            'Z' | '*' | 'Y' => PictureCategory::ZeroSuppress,
+            'V' => PictureCategory::DecimalPoint,
+            'S' | '+' | '-' => PictureCategory::Sign,
+            '$' => PictureCategory::Currency,
+            '.' | ',' | '/' | 'B' => PictureCategory::Insertion,
+            'C' | 'D' if matches!(
+                (c.to_ascii_uppercase(), bytes.get(i + 1).map(u8::to_ascii_uppercase)),
+                ('C', Some(b'R')) | ('D', Some(b'B'))
+            ) => {
+                len = 2;
+                PictureCategory::Credit
+            }
+            'T' | 'I' | 'R' => PictureCategory::Overpunch,
+            'E' | 'K' => PictureCategory::Exponent,
+            'A' | 'X' => PictureCategory::Character,
+            'F' => match factor(i + 1) {
+                Some((_, factor_len)) => {
+                    len += factor_len;
+                    PictureCategory::Scale
+                }
+                None => PictureCategory::Invalid,
+            },
+            _ => PictureCategory::Invalid,
+        };
+        i += len;
+        elements.push(PictureChar {
+            text: body[start..i].to_string(),
+            category,
+            count,
+            start: base + start,
+            end: base + i,
+        });
     }
+    
+    elements
+}
+
+/// Builtins that may also be assignment targets (`SUBSTR(S, 1, 3) = 'ABC';`)
+pub const PSEUDOVARIABLES: &[&str] = &["SUBSTR", "UNSPEC", "STRING", "ONCHAR"];
+
+/// Functional group of a builtin, for completion and hover tooling
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BuiltinCategory {
+    Math,
+    String,
+    Storage,
+    Array,
+    Condition,
+    DateTime,
+    Misc,
+}
+
+const BUILTIN_CATEGORIES: [(BuiltinCategory, &[&str]); 8] = [
+    (BuiltinCategory::Math, MATH_BUILTINS),
+    (BuiltinCategory::String, STRING_BUILTINS),
+    (BuiltinCategory::Storage, STORAGE_BUILTINS),
+    (BuiltinCategory::Array, ARRAY_BUILTINS),
+    (BuiltinCategory::Condition, CONDITION_BUILTINS),
+    (BuiltinCategory::DateTime, DATETIME_BUILTINS),
+    (BuiltinCategory::Misc, MISC_BUILTINS),
+    (BuiltinCategory::Misc, ORDINAL_BUILTINS),
+];
+
+/// Category of a builtin name (case-insensitive), None if it isn't a builtin
+pub fn builtin_category(name: &str) -> Option<BuiltinCategory> {
+    BUILTIN_CATEGORIES
+        .iter()
+        .find(|(_, names)| names.iter().any(|n| n.eq_ignore_ascii_case(name)))
+        .map(|(category, _)| *category)
 }
 
 /// Main tokenization function - called from JavaScript
 /// Returns a flat array: [type, start, end, type, start, end, ...]
-/// This is ~10x faster than returning objects
+/// This is ~10x faster than returning objects. The context post-passes
+/// (labels, pictures, conditions, pseudovariables, format items) are applied,
+/// so the codes match `tokenize`.
 #[wasm_bindgen]
 pub fn tokenize_flat(code: &str) -> Vec<u32> {
-    let mut result = Vec::with_capacity(code.len() / 2); // Pre-allocate
+    flatten(&tokenize_borrowed(code), 0)
+}
+
+/// `tokenize_flat`, optionally followed by an `Eof` record `[Eof, len, len]`
+/// for consumers that need an explicit end marker to flush state
+#[wasm_bindgen]
+pub fn tokenize_flat_eof(code: &str, emit_eof: bool) -> Vec<u32> {
+    let mut result = tokenize_flat(code);
+    if emit_eof {
+        result.extend_from_slice(&[TokenType::Eof as u32, code.len() as u32, code.len() as u32]);
+    }
+    result
+}
+
+/// Result of `tokenize_flat_limited`
+#[wasm_bindgen]
+#[derive(Debug, Clone)]
+pub struct LimitedTokens {
+    tokens: Vec<u32>,
+    truncated: bool,
+}
+
+#[wasm_bindgen]
+impl LimitedTokens {
+    /// Flat [type, start, end, ...] tokens; when `truncated`, the last one is
+    /// an `Unknown` covering everything after the limit
+    #[wasm_bindgen(getter)]
+    pub fn tokens(&self) -> Vec<u32> {
+        self.tokens.clone()
+    }
+    
+    /// True when the input held more than `max_tokens` tokens
+    #[wasm_bindgen(getter)]
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+/// `tokenize_flat` that stops after `max_tokens` tokens (no limit when
+/// `None`), so a huge paste can't allocate without bound. Whatever input
+/// remains becomes one trailing `Unknown` token and sets `truncated`. Types
+/// are the raw lexer's: the post-passes need the tokens past the cut, so a
+/// label comes out as `Identifier`.
+#[wasm_bindgen]
+pub fn tokenize_flat_limited(code: &str, max_tokens: Option<usize>) -> LimitedTokens {
+    let limit = max_tokens.unwrap_or(usize::MAX);
+    let mut tokens = Vec::with_capacity(estimated_tokens(code).min(limit.saturating_add(1)) * 3);
     let mut lexer = PLIToken::lexer(code);
+    let mut count = 0;
+    let mut end = 0;
     
-    while let Some(token_result) = lexer.next() {
-        let span = lexer.span();
-        let token_type = match token_result {
-            Ok(tok) => to_token_type(&tok) as u32,
-            Err(_) => TokenType::Unknown as u32,
-        };
-        
-        result.push(token_type);
-        result.push(span.start as u32);
-        result.push(span.end as u32);
+    while count < limit {
+        let Some((token_type, span)) = next_raw(&mut lexer) else { break };
+        tokens.push(token_type as u32);
+        tokens.push(span.start as u32);
+        tokens.push(span.end as u32);
+        end = span.end;
+        count += 1;
+    }
+    
+    let truncated = end < code.len();
+    if truncated {
+        tokens.extend_from_slice(&[TokenType::Unknown as u32, end as u32, code.len() as u32]);
+    }
+    LimitedTokens { tokens, truncated }
+}
+
+/// Bit set on the type word of trivia records in `tokenize_flat_tagged`
+pub const TRIVIA_FLAG: u32 = 1 << 31;
+
+/// `tokenize_flat` where the type word of whitespace, newline and comment
+/// records also has `TRIVIA_FLAG` (bit 31) set, so consumers skip trivia with
+/// one test. The low bits still hold the `TokenType` code:
+/// `type & 0x7fffffff`.
+#[wasm_bindgen]
+pub fn tokenize_flat_tagged(code: &str) -> Vec<u32> {
+    let mut result = tokenize_flat(code);
+    for record in result.chunks_exact_mut(3) {
+        if TokenType::from_code(record[0]).is_some_and(TokenType::is_trivia) {
+            record[0] |= TRIVIA_FLAG;
+        }
+    }
+    result
+}
+
+/// Output buffer size hint. Indented source runs at about 0.45 tokens per byte
+/// (benches/tokenize.rs fixture), so half the byte length covers typical input
+/// without reallocating.
+fn estimated_tokens(code: &str) -> usize {
+    code.len() / 2 + 16
+}
+
+/// Size in bytes of one `tokenize_packed` record.
+pub const PACKED_RECORD_SIZE: usize = 9;
+
+/// Tokenize into packed little-endian records, one per token:
+/// byte 0 is the type code, bytes 1..5 the `u32` start, bytes 5..9 the `u32` end.
+/// Records are unaligned, so read them through a `DataView` on the returned `Uint8Array`.
+#[wasm_bindgen]
+pub fn tokenize_packed(code: &str) -> Vec<u8> {
+    let tokens = tokenize_borrowed(code);
+    let mut result = Vec::with_capacity(tokens.len() * PACKED_RECORD_SIZE);
+    
+    for token in &tokens {
+        result.push(token.token_type as u8);
+        result.extend_from_slice(&(token.start as u32).to_le_bytes());
+        result.extend_from_slice(&(token.end as u32).to_le_bytes());
     }
     
     result
 }
 
-/// Tokenize and return JSON string (for easier debugging)
+/// The source text of `token`; `code` must be the string it was lexed from
+pub fn slice_token<'a>(code: &'a str, token: &Token) -> &'a str {
+    debug_assert!(
+        code.is_char_boundary(token.start) && code.is_char_boundary(token.end),
+        "token span {}..{} is not char aligned",
+        token.start,
+        token.end
+    );
+    &code[token.start..token.end]
+}
+
+/// Text of the token at byte offsets `start..end` (as in `tokenize_flat`), so
+/// JS can fetch one token without mirroring byte offsets itself. Empty when the
+/// span is out of range or not on char boundaries.
+#[wasm_bindgen]
+pub fn token_text_at(code: &str, start: usize, end: usize) -> String {
+    code.get(start..end).unwrap_or_default().to_string()
+}
+
+/// Tokenize and return JSON string (for easier debugging). The output is the
+/// same as serializing `tokenize`, but each token is written straight from its
+/// borrowed form, so no `Vec<Token>` of owned texts is built. The post-passes
+/// need the whole token list, so the `Vec<TokenRef>` remains.
 #[wasm_bindgen]
 pub fn tokenize_json(code: &str) -> String {
-    let tokens = tokenize(code);
-    serde_json::to_string(&tokens).unwrap_or_else(|_| "[]".to_string())
+    let tokens = tokenize_borrowed(code);
+    // A serialized token runs about 70-90 bytes
+    let mut json = Vec::with_capacity(tokens.len() * 80 + 2);
+    match write_json_tokens(&tokens, &mut json) {
+        Ok(()) => String::from_utf8(json).unwrap_or_else(|_| "[]".to_string()),
+        Err(_) => "[]".to_string(),
+    }
 }
 
-/// Internal tokenization returning Token structs
-pub fn tokenize(code: &str) -> Vec<Token> {
-    let mut tokens = Vec::with_capacity(code.len() / 4);
+fn write_json_tokens(tokens: &[TokenRef<'_>], out: &mut Vec<u8>) -> serde_json::Result<()> {
+    use serde::ser::{SerializeSeq, Serializer};
+    
+    let mut serializer = serde_json::Serializer::new(out);
+    let mut seq = serializer.serialize_seq(Some(tokens.len()))?;
+    for token in tokens {
+        seq.serialize_element(&JsonToken { token, canonical: canonical_spelling(token) })?;
+    }
+    seq.end()
+}
+
+/// A `TokenRef` serialized with the fields of `Token`, `canonical` included
+#[derive(Serialize)]
+struct JsonToken<'t, 'a> {
+    #[serde(flatten)]
+    token: &'t TokenRef<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    canonical: Option<String>,
+}
+
+/// Like `tokenize_flat`, but each entry also carries its 0-based line and column:
+/// [type, start, end, line, column, ...]. A single raw lexer pass: the context
+/// post-passes are skipped, so labels, pictures and the like keep their
+/// lexical type.
+#[wasm_bindgen]
+pub fn tokenize_flat_lc(code: &str) -> Vec<u32> {
+    let mut result = Vec::with_capacity(estimated_tokens(code) * 5);
     let mut lexer = PLIToken::lexer(code);
+    let mut pos = LineTracker::default();
     
-    while let Some(token_result) = lexer.next() {
-        let span = lexer.span();
-        let slice = lexer.slice();
-        
-        let token_type = match token_result {
-            Ok(tok) => to_token_type(&tok),
-            Err(_) => TokenType::Unknown,
-        };
-        
-        tokens.push(Token {
-            text: slice.to_string(),
-            token_type,
-            start: span.start,
-            end: span.end,
-        });
+    while let Some((token_type, span)) = next_raw(&mut lexer) {
+        result.push(token_type as u32);
+        result.push(span.start as u32);
+        result.push(span.end as u32);
+        result.push(pos.line as u32);
+        result.push(pos.column as u32);
+        pos.advance(&code[span]);
     }
     
-    tokens
+    result
 }
 
-/// Incremental tokenization - only re-tokenize changed region
-/// Returns tokens for the specified byte range
+/// Like `tokenize_flat`, but `start`/`end` are UTF-16 code-unit offsets, the
+/// unit Monaco and JavaScript strings index by. Raw lexer types only, without
+/// the context post-passes.
 #[wasm_bindgen]
-pub fn tokenize_range(code: &str, start_byte: usize, end_byte: usize) -> Vec<u32> {
-    // Find line boundaries
-    let start = code[..start_byte].rfind('\n').map(|i| i + 1).unwrap_or(0);
-    let end = code[end_byte..].find('\n').map(|i| end_byte + i + 1).unwrap_or(code.len());
-    
-    let slice = &code[start..end];
-    let mut result = Vec::new();
-    let mut lexer = PLIToken::lexer(slice);
+pub fn tokenize_flat_utf16(code: &str) -> Vec<u32> {
+    let mut result = Vec::with_capacity(estimated_tokens(code) * 3);
+    let mut lexer = PLIToken::lexer(code);
+    let mut offset = 0u32;
     
-    while let Some(token_result) = lexer.next() {
-        let span = lexer.span();
-        let token_type = match token_result {
-            Ok(tok) => to_token_type(&tok) as u32,
-            Err(_) => TokenType::Unknown as u32,
-        };
+    while let Some((token_type, span)) = next_raw(&mut lexer) {
+        let slice = &code[span];
+        let length = if slice.is_ascii() { slice.len() as u32 } else { slice.encode_utf16().count() as u32 };
         
-        // Adjust offsets to original code position
-        result.push(token_type);
-        result.push((start + span.start) as u32);
-        result.push((start + span.end) as u32);
+        result.push(token_type as u32);
+        result.push(offset);
+        result.push(offset + length);
+        offset += length;
     }
     
     result
 }
 
-/// Get version info
+/// Internal tokenization returning Token structs
+pub fn tokenize(code: &str) -> Vec<Token> {
+    tokenize_borrowed(code).into_iter().map(Token::from).collect()
+}
+
+/// Zero-copy tokenization: token texts are slices of `code`
+pub fn tokenize_borrowed(code: &str) -> Vec<TokenRef<'_>> {
+    collect_stream(TokenStream::new(code))
+}
+
+/// `tokenize` with dialect switches
+pub fn tokenize_with_lex_options(code: &str, options: &LexOptions) -> Vec<Token> {
+    collect_stream(TokenStream::with_options(code, *options)).into_iter().map(Token::from).collect()
+}
+
+/// `tokenize_with_lex_options` in the flat layout of `tokenize_flat`
 #[wasm_bindgen]
-pub fn version() -> String {
-    env!("CARGO_PKG_VERSION").to_string()
+pub fn tokenize_flat_with_lex_options(code: &str, options: &LexOptions) -> Vec<u32> {
+    flatten(&collect_stream(TokenStream::with_options(code, *options)), 0)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// PL/I implementation whose vocabulary `tokenize_with_dialect` follows. The
+/// lexer itself knows every word; a dialect only demotes the ones it lacks to
+/// `Identifier`, so plain `tokenize` behaves like `IbmEnterprise`.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dialect {
+    /// ANSI X3.53 full PL/I
+    Ansi,
+    /// IBM Enterprise PL/I for z/OS
+    #[default]
+    IbmEnterprise,
+    /// IBM PL/I for MVS & VM and OS PL/I
+    Pli390,
+    /// ANSI X3.74 general-purpose subset (Subset G)
+    Subset,
+}
+
+/// Enterprise PL/I additions: type definitions, handles, by-value linkage and
+/// the UPTHRU/DOWNTHRU loop forms
+const ENTERPRISE_WORDS: &[&str] = &[
+    "DEFINE", "ALIAS", "ORDINAL", "STRUCTURE", "UNION", "TYPE", "VALUE", "HANDLE",
+    "BYADDR", "BYVALUE", "ASSIGNABLE", "NONASSIGNABLE", "UPTHRU", "DOWNTHRU",
+    "ORDINALNAME", "ORDINALVALUE", "ORDINALPRED", "ORDINALSUCC",
+];
+
+/// Full-language features Subset G leaves out
+const FULL_LANGUAGE_WORDS: &[&str] = &["COMPLEX", "CONTROLLED"];
+
+impl Dialect {
+    /// Word lists this dialect does not have
+    fn missing_words(self) -> &'static [&'static [&'static str]] {
+        match self {
+            Dialect::IbmEnterprise => &[],
+            Dialect::Pli390 => &[ENTERPRISE_WORDS],
+            // Multitasking is an IBM extension
+            Dialect::Ansi => &[ENTERPRISE_WORDS, TASKING_KEYWORDS],
+            Dialect::Subset => &[ENTERPRISE_WORDS, TASKING_KEYWORDS, FULL_LANGUAGE_WORDS],
+        }
+    }
+}
+
+/// `tokenize` with the keywords and builtins of `dialect`: words it lacks
+/// (`HANDLE` or `UNION` outside Enterprise PL/I) become identifiers
+pub fn tokenize_with_dialect(code: &str, dialect: Dialect) -> Vec<Token> {
+    dialect_refs(code, dialect).into_iter().map(Token::from).collect()
+}
+
+/// `tokenize_with_dialect` in the flat layout of `tokenize_flat`
+#[wasm_bindgen]
+pub fn tokenize_flat_with_dialect(code: &str, dialect: Dialect) -> Vec<u32> {
+    flatten(&dialect_refs(code, dialect), 0)
+}
+
+fn dialect_refs(code: &str, dialect: Dialect) -> Vec<TokenRef<'_>> {
+    let mut tokens = tokenize_borrowed(code);
+    let missing = dialect.missing_words();
+    for token in tokens.iter_mut() {
+        let is_vocabulary = token.token_type.is_keyword() || token.token_type == TokenType::Builtin;
+        if is_vocabulary && missing.iter().any(|words| words.iter().any(|w| token.text.eq_ignore_ascii_case(w))) {
+            token.token_type = TokenType::Identifier;
+        }
+    }
+    tokens
+}
+
+fn collect_stream(mut stream: TokenStream<'_>) -> Vec<TokenRef<'_>> {
+    let mut tokens = Vec::with_capacity(estimated_tokens(stream.lexer.source()));
+    while let Some(token) = stream.next_ref() {
+        tokens.push(token);
+    }
+    post_process(&mut tokens);
+    if stream.options.context_sensitive_io {
+        classify_io_options(&mut tokens);
+    }
+    tokens
+}
+
+/// `tokenize` without whitespace and newlines, and without comments unless
+/// `keep_comments`. Trivia is dropped as it is lexed rather than filtered
+/// afterwards; offsets and positions still refer to the original source.
+pub fn tokenize_significant(code: &str, keep_comments: bool) -> Vec<Token> {
+    let mut stream = TokenStream::new(code);
+    let mut tokens = Vec::with_capacity(code.len() / 8);
+    while let Some(token) = stream.next_ref() {
+        if !token.token_type.is_trivia() || (keep_comments && token.token_type == TokenType::Comment) {
+            tokens.push(token);
+        }
+    }
+    // The post-passes skip trivia themselves, so they see the same sequence
+    post_process(&mut tokens);
+    tokens.into_iter().map(Token::from).collect()
+}
+
+/// Source with every comment removed, or with `preserve_length` blanked out
+/// byte for byte (newlines kept) so offsets and line numbers stay valid
+#[wasm_bindgen]
+pub fn strip_comments(code: &str, preserve_length: bool) -> String {
+    let mut result = String::with_capacity(code.len());
+    let mut stream = TokenStream::new(code);
     
-    #[test]
-    fn test_basic_tokenization() {
-        let code = "DCL X FIXED BINARY(31);";
-        let tokens = tokenize(code);
-        
-        assert!(!tokens.is_empty());
-        assert_eq!(tokens[0].token_type, TokenType::Keyword); // DCL
+    while let Some(token) = stream.next_ref() {
+        if token.token_type != TokenType::Comment {
+            result.push_str(token.text);
+        } else if preserve_length {
+            result.extend(token.text.bytes().map(|b| if b == b'\n' || b == b'\r' { b as char } else { ' ' }));
+        }
     }
     
-    #[test]
-    fn test_comment() {
-        let code = "/* This is a comment */ DCL X;";
-        let tokens = tokenize(code);
-        
-        assert_eq!(tokens[0].token_type, TokenType::Comment);
+    result
+}
+
+/// Source with all whitespace and newlines dropped (and comments too, unless
+/// `keep_comments`). A single space survives only between tokens that would
+/// otherwise lex as one, such as two words, `'A' 'B'` or `1 E+2`; strings and
+/// kept comments are copied verbatim.
+#[wasm_bindgen]
+pub fn minify(code: &str, keep_comments: bool) -> String {
+    // Every kept token back to back, with where each one ends
+    let mut packed = String::with_capacity(code.len());
+    let mut ends = Vec::new();
+    let mut stream = TokenStream::new(code);
+    while let Some(token) = stream.next_ref() {
+        if !token.token_type.is_trivia_with(!keep_comments) && !token.text.is_empty() {
+            packed.push_str(token.text);
+            ends.push(packed.len());
+        }
     }
     
-    #[test]
-    fn test_string() {
-        let code = "X = 'Hello World';";
-        let tokens = tokenize(code);
-        
-        let string_token = tokens.iter().find(|t| t.token_type == TokenType::String);
-        assert!(string_token.is_some());
+    // A token needs a space after it when, relexed against everything that
+    // follows unspaced, it would run on into its neighbour
+    let mut result = String::with_capacity(packed.len());
+    let mut start = 0;
+    for &end in &ends {
+        result.push_str(&packed[start..end]);
+        let mut lexer = PLIToken::lexer(&packed[start..]);
+        if end < packed.len() && next_raw(&mut lexer).is_some_and(|(_, span)| start + span.end != end) {
+            result.push(' ');
+        }
+        start = end;
     }
     
-    #[test]
-    fn test_preprocessor() {
+    result
+}
+
+/// Highlighted HTML with no JS layer needed: every token other than whitespace
+/// and newlines becomes `<span class="{class_prefix}{type}">text</span>`, with
+/// the lowercase type name (`pli-keyword`, `pli-stringdouble`). Whitespace and
+/// newlines are copied verbatim; `<`, `>` and `&` are escaped everywhere.
+#[wasm_bindgen]
+pub fn highlight_html(code: &str, class_prefix: &str) -> String {
+    let mut html = String::with_capacity(code.len() * 2);
+    for token in tokenize_borrowed(code) {
+        let wrap = !matches!(token.token_type, TokenType::Whitespace | TokenType::Newline);
+        if wrap {
+            html.push_str("<span class=\"");
+            html.push_str(class_prefix);
+            html.push_str(token.token_type.name());
+            html.push_str("\">");
+        }
+        for c in token.text.chars() {
+            match c {
+                '<' => html.push_str("&lt;"),
+                '>' => html.push_str("&gt;"),
+                '&' => html.push_str("&amp;"),
+                _ => html.push(c),
+            }
+        }
+        if wrap {
+            html.push_str("</span>");
+        }
+    }
+    html
+}
+
+/// SGR parameters (`"1;34"`, `"38;5;208"`) per token type for `highlight_ansi`.
+/// `default()` is a conventional 16-color palette; types without a code are
+/// printed uncolored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnsiTheme {
+    codes: Vec<Option<String>>,
+}
+
+impl AnsiTheme {
+    /// A theme that colors nothing
+    pub fn plain() -> AnsiTheme {
+        AnsiTheme { codes: vec![None; TokenType::ALL.len()] }
+    }
+    
+    /// Set (or with `None`, clear) the SGR parameters for `token_type`
+    pub fn set(&mut self, token_type: TokenType, sgr: Option<&str>) -> &mut AnsiTheme {
+        self.codes[token_type as usize] = sgr.map(str::to_string);
+        self
+    }
+    
+    /// SGR parameters for `token_type`, if it is colored
+    pub fn color(&self, token_type: TokenType) -> Option<&str> {
+        self.codes[token_type as usize].as_deref()
+    }
+}
+
+impl Default for AnsiTheme {
+    fn default() -> Self {
+        let mut theme = AnsiTheme::plain();
+        for &token_type in TokenType::ALL {
+            let sgr = match token_type {
+                t if t.is_keyword() => "1;34",
+                t if t.is_string() => "32",
+                TokenType::Picture => "32",
+                TokenType::Comment => "2;37",
+                TokenType::Number => "36",
+                TokenType::Preprocessor | TokenType::Directive => "35",
+                TokenType::Builtin | TokenType::Pseudovariable => "33",
+                TokenType::Condition | TokenType::FormatItem => "34",
+                TokenType::Label => "1",
+                TokenType::SequenceNumber => "2",
+                TokenType::Unknown => "31",
+                _ => continue,
+            };
+            theme.set(token_type, Some(sgr));
+        }
+        theme
+    }
+}
+
+/// Terminal-colored source for CLI use: each token `theme` colors is wrapped
+/// in `ESC[{sgr}m ... ESC[0m`; whitespace, newlines and uncolored tokens pass
+/// through unchanged, so stripping the escapes gives back `code`.
+pub fn highlight_ansi(code: &str, theme: &AnsiTheme) -> String {
+    let mut out = String::with_capacity(code.len() * 2);
+    for token in tokenize_borrowed(code) {
+        let sgr = match token.token_type {
+            TokenType::Whitespace | TokenType::Newline => None,
+            token_type => theme.color(token_type),
+        };
+        match sgr {
+            Some(sgr) => {
+                out.push_str("\x1b[");
+                out.push_str(sgr);
+                out.push('m');
+                out.push_str(token.text);
+                out.push_str("\x1b[0m");
+            }
+            None => out.push_str(token.text),
+        }
+    }
+    out
+}
+
+/// Like `tokenize`, but consecutive `Whitespace` tokens are merged into one.
+/// With `fold_newlines`, whole runs of whitespace and newlines (for example
+/// Whitespace + Newline + Whitespace) become a single `Whitespace` token.
+/// Merged tokens keep exact byte offsets and the position of their first piece.
+pub fn tokenize_collapsed(code: &str, fold_newlines: bool) -> Vec<Token> {
+    let mut merged: Vec<TokenRef<'_>> = Vec::new();
+    
+    for token in tokenize_borrowed(code) {
+        let mergeable = |t: &TokenRef<'_>| {
+            t.token_type == TokenType::Whitespace || (fold_newlines && t.token_type == TokenType::Newline)
+        };
+        match merged.last_mut() {
+            Some(last) if mergeable(last) && mergeable(&token) && last.end == token.start => {
+                last.end = token.end;
+                last.text = &code[last.start..last.end];
+                last.token_type = TokenType::Whitespace;
+            }
+            _ => merged.push(token),
+        }
+    }
+    
+    merged.into_iter().map(Token::from).collect()
+}
+
+/// Case normalization for `tokenize_folded`
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CaseFold {
+    #[default]
+    None,
+    Upper,
+    Lower,
+}
+
+/// `tokenize_folded` serialized as `Token[]`
+#[wasm_bindgen(js_name = tokenize_folded)]
+pub fn tokenize_folded_js(code: &str, case_fold: CaseFold) -> JsValue {
+    serde_wasm_bindgen::to_value(&tokenize_folded(code, case_fold)).unwrap_or(JsValue::NULL)
+}
+
+/// `tokenize` for canonical display: the text of names (identifiers, labels,
+/// keywords and builtins) is upper- or lowercased; strings, comments and
+/// numbers are left as written. Only ASCII letters fold, so every text keeps
+/// its length and offsets still index the original `code`; a non-ASCII
+/// letter in a name stays as it is.
+pub fn tokenize_folded(code: &str, case_fold: CaseFold) -> Vec<Token> {
+    let mut tokens = tokenize(code);
+    for token in tokens.iter_mut().filter(|t| t.token_type.is_word() || t.token_type == TokenType::Label) {
+        match case_fold {
+            CaseFold::None => {}
+            CaseFold::Upper => token.text.make_ascii_uppercase(),
+            CaseFold::Lower => token.text.make_ascii_lowercase(),
+        }
+    }
+    tokens
+}
+
+/// Like `tokenize`, but identifiers named in `extra_keywords` (any case) become
+/// `Keyword` tokens, so shops can highlight their own macro libraries
+pub fn tokenize_with_keywords(code: &str, extra_keywords: Vec<String>) -> Vec<Token> {
+    tokenize_keywords_borrowed(code, &extra_keywords).into_iter().map(Token::from).collect()
+}
+
+/// `tokenize_with_keywords` in the flat layout of `tokenize_flat`
+#[wasm_bindgen]
+pub fn tokenize_flat_with_keywords(code: &str, extra_keywords: Vec<String>) -> Vec<u32> {
+    flatten(&tokenize_keywords_borrowed(code, &extra_keywords), 0)
+}
+
+fn tokenize_keywords_borrowed<'a>(code: &'a str, extra_keywords: &[String]) -> Vec<TokenRef<'a>> {
+    let mut tokens = tokenize_borrowed(code);
+    upgrade_keywords(&mut tokens, &keyword_set(extra_keywords));
+    tokens
+}
+
+fn keyword_set(extra_keywords: &[String]) -> HashSet<String> {
+    extra_keywords.iter().map(|k| k.to_ascii_uppercase()).collect()
+}
+
+/// Identifiers in `keywords` (uppercase) become `Keyword`
+fn upgrade_keywords(tokens: &mut [TokenRef<'_>], keywords: &HashSet<String>) {
+    if keywords.is_empty() {
+        return;
+    }
+    for token in tokens.iter_mut().filter(|t| t.token_type == TokenType::Identifier) {
+        if keywords.contains(&token.text.to_ascii_uppercase()) {
+            token.token_type = TokenType::Keyword;
+        }
+    }
+}
+
+/// Opt-in lexing extensions for PL/I-derived dialects; the default is
+/// standard PL/I
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LexOptions {
+    /// `--` through end of line is a `Comment` instead of two `-` operators
+    pub line_comments: bool,
+    /// `@` may appear in identifiers
+    pub allow_at: bool,
+    /// `#` may appear in identifiers
+    pub allow_hash: bool,
+    /// `$` may appear in identifiers
+    pub allow_dollar: bool,
+    /// Stream I/O options (`LIST`, `DATA`, `EDIT`, `SKIP`, `PAGE`, `LINE`,
+    /// `COLUMN`) are keywords only inside I/O statements, identifiers elsewhere
+    pub context_sensitive_io: bool,
+    /// A line whose first non-blank character is `*` is a `Comment` card
+    /// (`*PROCESS` cards stay directives)
+    pub star_comment_col1: bool,
+}
+
+impl Default for LexOptions {
+    fn default() -> Self {
+        LexOptions {
+            line_comments: false,
+            allow_at: true,
+            allow_hash: true,
+            allow_dollar: true,
+            context_sensitive_io: false,
+            star_comment_col1: false,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl LexOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> LexOptions {
+        LexOptions::default()
+    }
+}
+
+impl LexOptions {
+    /// Whether `c` may appear in an identifier under these options
+    fn allows(&self, c: char) -> bool {
+        match c {
+            '@' => self.allow_at,
+            '#' => self.allow_hash,
+            '$' => self.allow_dollar,
+            _ => true,
+        }
+    }
+}
+
+/// Split a name containing national characters that `options` excludes: each
+/// excluded char becomes `Unknown` and the runs between them are lexed afresh
+/// (`A#1B` gives `A`, `#`, `1B`). Logos can't vary the identifier pattern at
+/// run time, so this runs after the fact on the few names it affects.
+fn split_name(text: &str, start: usize, options: &LexOptions) -> Vec<(TokenType, Range<usize>)> {
+    fn lex_run(run: &str, run_start: usize, pieces: &mut Vec<(TokenType, Range<usize>)>) {
+        let mut lexer = PLIToken::lexer(run);
+        while let Some((token_type, span)) = next_raw(&mut lexer) {
+            pieces.push((token_type, run_start + span.start..run_start + span.end));
+        }
+    }
+    
+    let mut pieces = Vec::new();
+    let mut run_start = 0;
+    for (i, c) in text.char_indices().filter(|&(_, c)| !options.allows(c)) {
+        lex_run(&text[run_start..i], start + run_start, &mut pieces);
+        run_start = i + c.len_utf8();
+        pieces.push((TokenType::Unknown, start + i..start + run_start));
+    }
+    lex_run(&text[run_start..], start + run_start, &mut pieces);
+    pieces
+}
+
+/// Lazy token iterator over the source - tokens are produced one at a time
+/// straight from the Logos lexer, so memory stays bounded on huge inputs.
+/// Context-dependent post-passes (labels) need the whole vector and are only
+/// applied by `tokenize`.
+pub struct TokenStream<'a> {
+    lexer: logos::Lexer<'a, PLIToken>,
+    pos: LineTracker,
+    options: LexOptions,
+    /// Remaining pieces of a split name, last piece first
+    pending: Vec<(TokenType, Range<usize>)>,
+}
+
+impl<'a> TokenStream<'a> {
+    pub fn new(code: &'a str) -> Self {
+        TokenStream::with_options(code, LexOptions::default())
+    }
+    
+    pub fn with_options(code: &'a str, options: LexOptions) -> Self {
+        TokenStream { lexer: PLIToken::lexer(code), pos: LineTracker::default(), options, pending: Vec::new() }
+    }
+    
+    /// Advance without copying the token text
+    pub fn next_ref(&mut self) -> Option<TokenRef<'a>> {
+        let (token_type, span) = match self.pending.pop() {
+            Some(piece) => piece,
+            None => self.lex_next()?,
+        };
+        let slice = &self.lexer.source()[span.clone()];
+        
+        let token = TokenRef {
+            text: slice,
+            token_type,
+            start: span.start,
+            end: span.end,
+            line: self.pos.line,
+            column: self.pos.column,
+        };
+        self.pos.advance(slice);
+        Some(token)
+    }
+    
+    fn lex_next(&mut self) -> Option<(TokenType, Range<usize>)> {
+        let (mut token_type, mut span) = next_raw(&mut self.lexer)?;
+        
+        if self.options.line_comments && self.lexer.slice() == "-" && self.lexer.remainder().starts_with('-') {
+            let rest = self.lexer.remainder();
+            self.lexer.bump(rest.find(['\n', '\r']).unwrap_or(rest.len()));
+            token_type = TokenType::Comment;
+            span = self.lexer.span();
+        }
+        if self.options.star_comment_col1
+            && token_type == TokenType::Operator
+            && self.lexer.slice().starts_with('*')
+            && starts_line(self.lexer.source(), span.start)
+        {
+            let rest = self.lexer.remainder();
+            self.lexer.bump(rest.find(['\n', '\r']).unwrap_or(rest.len()));
+            token_type = TokenType::Comment;
+            span = self.lexer.span();
+        }
+        
+        let slice = &self.lexer.source()[span.clone()];
+        let is_name = matches!(token_type, TokenType::Identifier | TokenType::Preprocessor);
+        if is_name && slice.contains(|c| !self.options.allows(c)) {
+            self.pending = split_name(slice, span.start, &self.options);
+            self.pending.reverse();
+            return self.pending.pop();
+        }
+        Some((token_type, span))
+    }
+}
+
+impl Iterator for TokenStream<'_> {
+    type Item = Token;
+    
+    fn next(&mut self) -> Option<Token> {
+        self.next_ref().map(Token::from)
+    }
+}
+
+/// Tokenize and group the tokens per source line, serialized as `Token[][]`
+/// with one (possibly empty) array per line
+#[wasm_bindgen]
+pub fn tokenize_lines(code: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&tokens_by_line(code)).unwrap_or(JsValue::NULL)
+}
+
+/// Tokens bucketed by line. A token spanning newlines (a multi-line comment or
+/// string) is split after each newline so every line gets its own piece with
+/// adjusted spans. Each line keeps its terminating newline, so the texts of a
+/// bucket concatenate to the full line.
+pub fn tokens_by_line(code: &str) -> Vec<Vec<Token>> {
+    let mut lines: Vec<Vec<Token>> = vec![Vec::new(); line_break_count(code) + 1];
+    
+    for token in tokenize(code) {
+        if token.token_type == TokenType::Newline || line_break_count(&token.text) == 0 {
+            lines[token.line].push(token);
+            continue;
+        }
+        
+        let mut start = token.start;
+        let mut column = token.column;
+        for (line, piece) in (token.line..).zip(split_lines_inclusive(&token.text)) {
+            lines[line].push(Token {
+                text: piece.to_string(),
+                token_type: token.token_type,
+                start,
+                end: start + piece.len(),
+                line,
+                column,
+                canonical: None,
+            });
+            start += piece.len();
+            column = 0;
+        }
+    }
+    
+    lines
+}
+
+/// A stable 64-bit hash per line (FNV-1a over each token's type, line-relative
+/// span and text, from `tokens_by_line`), so editors can skip re-rendering
+/// lines whose fingerprint is unchanged. Identical lines hash identically
+/// wherever they sit in the file, with one caveat: lexer state carried in
+/// from above still counts, so opening or closing a multi-line comment (or
+/// a context-dependent classification such as a label) legitimately changes
+/// the fingerprints of the lines it affects.
+#[wasm_bindgen]
+pub fn line_fingerprints(code: &str) -> Vec<u64> {
+    tokens_by_line(code)
+        .iter()
+        .map(|line| {
+            let line_start = line.first().map_or(0, |t| t.start);
+            line.iter().fold(FNV_OFFSET_BASIS, |hash, token| {
+                let span = [token.token_type as u32, (token.start - line_start) as u32, (token.end - line_start) as u32];
+                let hash = span.iter().fold(hash, |hash, n| fnv1a(hash, &n.to_le_bytes()));
+                fnv1a(hash, token.text.as_bytes())
+            })
+        })
+        .collect()
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Formatting-insensitive hash of `code` for duplicate detection, with every
+/// identifier and label replaced by one placeholder, so fragments differing
+/// only in whitespace, comments, case or names hash equal
+#[wasm_bindgen]
+pub fn normalized_hash(code: &str) -> u64 {
+    normalized_hash_with(code, true)
+}
+
+/// `normalized_hash`, where unless `normalize_identifiers` names keep their
+/// (uppercased) spelling, so only layout, comments and case are ignored.
+/// Strings and pictures always hash as written.
+#[wasm_bindgen]
+pub fn normalized_hash_with(code: &str, normalize_identifiers: bool) -> u64 {
+    tokenize_borrowed(code)
+        .iter()
+        .filter(|t| !t.token_type.is_trivia())
+        .fold(FNV_OFFSET_BASIS, |hash, token| {
+            let is_name = matches!(token.token_type, TokenType::Identifier | TokenType::Label);
+            let text = if is_name && normalize_identifiers {
+                String::new()
+            } else if token.token_type.is_string() || token.token_type == TokenType::Picture {
+                token.text.to_string()
+            } else {
+                token.text.to_ascii_uppercase()
+            };
+            // Length-prefixed, so token boundaries are part of the hash
+            let hash = fnv1a(hash, &(token.token_type as u32).to_le_bytes());
+            let hash = fnv1a(hash, &(text.len() as u32).to_le_bytes());
+            fnv1a(hash, text.as_bytes())
+        })
+}
+
+/// True when `a` and `b` have the same significant tokens - same types, same
+/// texts - so they differ only in layout and comments, as after a reformat.
+/// Like `normalized_hash_with(_, false)`, words compare case-insensitively
+/// while strings and pictures must match exactly. Unlike a hash, this cannot
+/// collide.
+#[wasm_bindgen]
+pub fn tokens_equivalent(a: &str, b: &str) -> bool {
+    let (a, b) = (tokenize_borrowed(a), tokenize_borrowed(b));
+    let mut a = a.iter().filter(|t| !t.token_type.is_trivia());
+    let mut b = b.iter().filter(|t| !t.token_type.is_trivia());
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return true,
+            (Some(x), Some(y)) if x.token_type == y.token_type => {
+                let verbatim = x.token_type.is_string() || x.token_type == TokenType::Picture;
+                let same = if verbatim { x.text == y.text } else { x.text.eq_ignore_ascii_case(y.text) };
+                if !same {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// Index of the first token after `idx` that isn't whitespace or a newline,
+/// nor a comment when `comments_are_trivia` (the usual choice)
+pub fn next_significant<T: Typed>(tokens: &[T], idx: usize, comments_are_trivia: bool) -> Option<usize> {
+    (idx.saturating_add(1)..tokens.len()).find(|&i| !tokens[i].token_type().is_trivia_with(comments_are_trivia))
+}
+
+/// Index of the last token before `idx` that isn't whitespace or a newline,
+/// nor a comment when `comments_are_trivia`
+pub fn prev_significant<T: Typed>(tokens: &[T], idx: usize, comments_are_trivia: bool) -> Option<usize> {
+    (0..idx.min(tokens.len())).rev().find(|&i| !tokens[i].token_type().is_trivia_with(comments_are_trivia))
+}
+
+/// Type and text of `Token` and `TokenRef` alike, so the significant-token
+/// helpers and the post-passes work over either
+pub trait Typed {
+    fn token_type(&self) -> TokenType;
+    fn text(&self) -> &str;
+}
+
+impl Typed for Token {
+    fn token_type(&self) -> TokenType {
+        self.token_type
+    }
+    
+    fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+impl Typed for TokenRef<'_> {
+    fn token_type(&self) -> TokenType {
+        self.token_type
+    }
+    
+    fn text(&self) -> &str {
+        self.text
+    }
+}
+
+/// Tracks where statements begin while the passes walk significant tokens:
+/// at the start of input, after `;`, after a label's `:` (so `A: B: PROC;`
+/// starts twice), after THEN/ELSE/OTHERWISE and after the `)` closing a WHEN
+/// clause's selector list (`WHEN (1) X = 2;`).
+struct StatementStart {
+    at_start: bool,
+    after_label: bool,
+    when_depth: Option<usize>,
+}
+
+impl StatementStart {
+    fn new() -> Self {
+        StatementStart { at_start: true, after_label: false, when_depth: None }
+    }
+    
+    /// Whether the next token fed to `advance` begins a statement
+    fn at_start(&self) -> bool {
+        self.at_start
+    }
+    
+    fn advance<T: Typed>(&mut self, token: &T) {
+        let (token_type, text) = (token.token_type(), token.text());
+        let keyword_is = |word: &str| token_type.is_keyword() && text.eq_ignore_ascii_case(word);
+        if self.at_start && keyword_is("WHEN") {
+            self.when_depth = Some(0);
+        }
+        
+        let closes_when = match (self.when_depth, text) {
+            (Some(depth), "(") => {
+                self.when_depth = Some(depth + 1);
+                false
+            }
+            (Some(1), ")") => {
+                self.when_depth = None;
+                true
+            }
+            (Some(depth), ")") => {
+                self.when_depth = Some(depth.saturating_sub(1));
+                false
+            }
+            _ => false,
+        };
+        self.at_start = match token_type {
+            TokenType::Punctuation if text == ";" => {
+                self.when_depth = None;
+                true
+            }
+            TokenType::Punctuation if text == ":" => self.after_label,
+            _ => closes_when || keyword_is("THEN") || keyword_is("ELSE") || keyword_is("OTHERWISE"),
+        };
+        self.after_label = token_type == TokenType::Label;
+    }
+}
+
+/// Context-dependent reclassification applied after lexing
+fn post_process(tokens: &mut [TokenRef<'_>]) {
+    classify_labels(tokens);
+    classify_pictures(tokens);
+    classify_conditions(tokens);
+    classify_loop_repeat(tokens);
+    classify_pseudovariables(tokens);
+    classify_format_items(tokens);
+    classify_asterisk_bounds(tokens);
+    classify_range_colons(tokens);
+    classify_preprocessor_statements(tokens);
+}
+
+/// Format codes that may start an item of a GET/PUT EDIT format list
+const FORMAT_CODES: &[&str] = &[
+    "A", "B", "B1", "B2", "B3", "B4", "C", "E", "F", "G", "L", "P", "R", "X",
+    "COLUMN", "COL", "LINE", "PAGE", "SKIP",
+];
+
+/// Post-pass: format codes in the format lists of GET/PUT EDIT. After EDIT the
+/// parenthesized groups alternate data list, format list (`PUT EDIT(X)(F(5,2))
+/// (Y)(A)`). Inside a format list a code starts an item: it follows the list's
+/// `(`, an item-separating `,` or an iteration factor (`3 F(5)`, `(3)A`). Only
+/// the code itself becomes `FormatItem`: its parenthesized arguments are
+/// ordinary expressions (`F(W+1, D)`, `A(LENGTH(S))`), so their numbers, names
+/// and builtins keep their own types.
+fn classify_format_items(tokens: &mut [TokenRef<'_>]) {
+    let mut in_get_put = false;
+    let mut after_edit = false;
+    let mut group = 0usize;
+    // One entry per open parenthesis: does it hold format items (not arguments)?
+    let mut item_level: Vec<bool> = Vec::new();
+    
+    for i in 0..tokens.len() {
+        if tokens[i].token_type.is_trivia() {
+            continue;
+        }
+        let token = &tokens[i];
+        let prev = prev_significant(tokens, i, true);
+        let keyword_is = |word: &str| token.token_type.is_keyword() && token.text.eq_ignore_ascii_case(word);
+        
+        if item_level.is_empty() {
+            match token.text {
+                ";" => {
+                    in_get_put = false;
+                    after_edit = false;
+                }
+                "(" if after_edit => {
+                    group += 1;
+                    item_level.push(group.is_multiple_of(2));
+                }
+                _ => {
+                    if keyword_is("GET") || keyword_is("PUT") {
+                        in_get_put = true;
+                    }
+                    after_edit = in_get_put && keyword_is("EDIT");
+                    group = 0;
+                    if token.text == "(" {
+                        item_level.push(false);
+                    }
+                }
+            }
+        } else {
+            let in_items = item_level.last() == Some(&true);
+            let prev_token = prev.map(|p| (tokens[p].text, tokens[p].token_type));
+            let starts_item = matches!(prev_token, Some(("(" | "," | ")", _)) | Some((_, TokenType::Number)));
+            match token.text {
+                "(" => {
+                    let after_code = prev.is_some_and(|p| tokens[p].token_type == TokenType::FormatItem);
+                    item_level.push(in_items && !after_code);
+                }
+                ")" => {
+                    item_level.pop();
+                }
+                _ if in_items && starts_item && token.token_type.is_word()
+                    && FORMAT_CODES.iter().any(|c| token.text.eq_ignore_ascii_case(c)) =>
+                {
+                    tokens[i].token_type = TokenType::FormatItem;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Post-pass: a pseudovariable-capable builtin in the target list of an
+/// assignment (`SUBSTR(S, 1, 3) = 'ABC';`, `A, UNSPEC(B) = C;`) becomes a
+/// `Pseudovariable`; on the right of the `=` it stays a `Builtin` call.
+/// Statements start where `StatementStart` says.
+fn classify_pseudovariables(tokens: &mut [TokenRef<'_>]) {
+    let mut statement = StatementStart::new();
+    
+    for i in 0..tokens.len() {
+        if tokens[i].token_type.is_trivia() {
+            continue;
+        }
+        if statement.at_start() {
+            for target in assignment_targets(tokens, i) {
+                tokens[target].token_type = TokenType::Pseudovariable;
+            }
+        }
+        statement.advance(&tokens[i]);
+    }
+}
+
+/// Stream I/O options that `LexOptions::context_sensitive_io` treats as keywords
+/// only inside an I/O statement
+const IO_OPTION_KEYWORDS: &[&str] = &["LIST", "DATA", "EDIT", "SKIP", "PAGE", "LINE", "COLUMN", "COL"];
+
+/// Statements whose options `IO_OPTION_KEYWORDS` are
+const IO_STATEMENTS: &[&str] = &["GET", "PUT", "OPEN", "CLOSE", "READ", "WRITE", "REWRITE", "DELETE", "DISPLAY"];
+
+/// Opt-in post-pass (`LexOptions::context_sensitive_io`): an I/O option
+/// keyword outside an I/O statement, as in `LINE = 5;`, is demoted to
+/// `Identifier`. An I/O statement runs from its GET/PUT/OPEN/... keyword to
+/// the next `;`, so it may follow an `ON` condition or `THEN` on the same line.
+fn classify_io_options(tokens: &mut [TokenRef<'_>]) {
+    let mut in_io_statement = false;
+    
+    for token in tokens.iter_mut() {
+        match token.token_type {
+            TokenType::Punctuation if token.text == ";" => in_io_statement = false,
+            TokenType::KeywordIo if IO_STATEMENTS.iter().any(|kw| token.text.eq_ignore_ascii_case(kw)) => {
+                in_io_statement = true;
+            }
+            TokenType::KeywordIo
+                if !in_io_statement && IO_OPTION_KEYWORDS.iter().any(|kw| token.text.eq_ignore_ascii_case(kw)) =>
+            {
+                token.token_type = TokenType::Identifier;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Pseudovariable builtins among the references of an assignment target list
+/// (`ref {, ref} =`) starting at `tokens[start]`; empty if the statement is
+/// not an assignment. A reference is a name with optional parenthesized
+/// arguments and `.` qualifiers.
+fn assignment_targets(tokens: &[TokenRef<'_>], start: usize) -> Vec<usize> {
+    let mut targets = Vec::new();
+    let mut name = start;
+    
+    loop {
+        if !tokens[name].token_type.is_word() {
+            return Vec::new();
+        }
+        if tokens[name].token_type == TokenType::Builtin
+            && PSEUDOVARIABLES.iter().any(|p| tokens[name].text.eq_ignore_ascii_case(p))
+        {
+            targets.push(name);
+        }
+        
+        // Arguments and qualifiers: skip balanced parentheses and `.name`
+        let mut depth = 0usize;
+        let mut next = next_significant(tokens, name, true);
+        while let Some(j) = next {
+            let after_dot = prev_significant(tokens, j, true).is_some_and(|p| tokens[p].text == ".");
+            match tokens[j].text {
+                "(" => depth += 1,
+                ")" if depth > 0 => depth -= 1,
+                ";" => return Vec::new(),
+                "." if depth == 0 => {}
+                _ if depth == 0 && !(tokens[j].token_type.is_word() && after_dot) => break,
+                _ => {}
+            }
+            next = next_significant(tokens, j, true);
+        }
+        
+        let Some(j) = next else { return Vec::new() };
+        match (tokens[j].text, tokens[j].token_type) {
+            ("=", TokenType::Operator) => return targets,
+            (",", _) => match next_significant(tokens, j, true) {
+                Some(following) => name = following,
+                None => return Vec::new(),
+            },
+            _ => return Vec::new(),
+        }
+    }
+}
+
+/// Post-pass: names inside a preprocessor statement (`%DCL N FIXED;`,
+/// `%PROC FOO(A) RETURNS(CHAR);`, `%N = N + 1;`) are preprocessor-scope and
+/// become `Preprocessor` up to the statement's `;`. Keywords keep their type,
+/// and `%INCLUDE` members stay identifiers. A statement is one started by a
+/// `%` keyword, or by a `%name` at the start of a statement.
+fn classify_preprocessor_statements(tokens: &mut [TokenRef<'_>]) {
+    let mut in_statement = false;
+    let mut at_statement_start = true;
+    
+    for token in tokens.iter_mut().filter(|t| !t.token_type.is_trivia()) {
+        match token.token_type {
+            TokenType::Punctuation if token.text == ";" => {
+                in_statement = false;
+                at_statement_start = true;
+                continue;
+            }
+            TokenType::Preprocessor if ["%INCLUDE", "%XINCLUDE"].iter().any(|kw| token.text.eq_ignore_ascii_case(kw)) => {
+                in_statement = false;
+            }
+            TokenType::Preprocessor
+                if at_statement_start || PREPROCESSOR_KEYWORDS.iter().any(|kw| token.text.eq_ignore_ascii_case(kw)) =>
+            {
+                in_statement = true;
+            }
+            TokenType::Identifier if in_statement => token.token_type = TokenType::Preprocessor,
+            _ => {}
+        }
+        at_statement_start = false;
+    }
+}
+
+/// Post-pass: a `*` standing alone as an item of a parenthesized list, such
+/// as the bounds of `DCL M(*,*)` or the cross-section `A(*, 1)`, is a
+/// placeholder and becomes `Punctuation`; `A*B` and `(*B)` keep the operator.
+fn classify_asterisk_bounds(tokens: &mut [TokenRef<'_>]) {
+    let mut depth = 0usize;
+    
+    for i in 0..tokens.len() {
+        match tokens[i].text {
+            "(" if tokens[i].token_type == TokenType::Punctuation => depth += 1,
+            ")" if tokens[i].token_type == TokenType::Punctuation => depth = depth.saturating_sub(1),
+            "*" if depth > 0 && tokens[i].token_type == TokenType::Operator => {
+                let before = prev_significant(tokens, i, true).map(|j| tokens[j].text);
+                let after = next_significant(tokens, i, true).map(|j| tokens[j].text);
+                if matches!(before, Some("(" | ",")) && matches!(after, Some(")" | ",")) {
+                    tokens[i].token_type = TokenType::Punctuation;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Post-pass: a `:` inside parentheses separates the bounds of a range - the
+/// extents of `DCL A(1:10)`, the cross-section `A(2:5)` - and becomes an
+/// `Operator`; at the top level it stays the label `Punctuation`.
+fn classify_range_colons(tokens: &mut [TokenRef<'_>]) {
+    let mut depth = 0usize;
+    
+    for token in tokens.iter_mut().filter(|t| t.token_type == TokenType::Punctuation) {
+        match token.text {
+            "(" => depth += 1,
+            ")" => depth = depth.saturating_sub(1),
+            ":" if depth > 0 => token.token_type = TokenType::Operator,
+            _ => {}
+        }
+    }
+}
+
+/// Post-pass: REPEAT outside parentheses in a DO statement (`DO J = 1 REPEAT
+/// J * 2`) is the loop keyword; everywhere else it stays the REPEAT builtin.
+fn classify_loop_repeat(tokens: &mut [TokenRef<'_>]) {
+    let mut in_do = false;
+    let mut depth = 0usize;
+    
+    for token in tokens.iter_mut().filter(|t| !t.token_type.is_trivia()) {
+        match token.text {
+            ";" => in_do = false,
+            "(" => depth += 1,
+            ")" => depth = depth.saturating_sub(1),
+            _ if token.token_type == TokenType::KeywordControl && token.text.eq_ignore_ascii_case("DO") => {
+                in_do = true;
+                depth = 0;
+            }
+            _ if in_do && depth == 0 && token.token_type == TokenType::Builtin && token.text.eq_ignore_ascii_case("REPEAT") => {
+                token.token_type = TokenType::Keyword;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Post-pass: the condition named after ON/SIGNAL/REVERT. `CONDITION(name)`
+/// tags both the keyword and the programmer-defined name; for forms with a
+/// file or label argument like `ENDFILE(SYSIN)` only the condition is tagged.
+fn classify_conditions(tokens: &mut [TokenRef<'_>]) {
+    let is_one_of = |token: &TokenRef<'_>, words: &[&str]| {
+        words.iter().any(|w| token.text.eq_ignore_ascii_case(w))
+    };
+    
+    for i in 0..tokens.len() {
+        if tokens[i].token_type != TokenType::Keyword || !is_one_of(&tokens[i], &["ON", "SIGNAL", "REVERT"]) {
+            continue;
+        }
+        let Some(name) = next_significant(tokens, i, true) else { continue };
+        if !tokens[name].token_type.is_word() {
+            continue;
+        }
+        tokens[name].token_type = TokenType::Condition;
+        
+        if !is_one_of(&tokens[name], &["CONDITION", "COND"]) {
+            continue;
+        }
+        let open = next_significant(tokens, name, true);
+        let user = open.and_then(|j| next_significant(tokens, j, true));
+        let close = user.and_then(|j| next_significant(tokens, j, true));
+        if let (Some(open), Some(user), Some(close)) = (open, user, close) {
+            if tokens[open].text == "(" && tokens[close].text == ")" && tokens[user].token_type.is_word() {
+                tokens[user].token_type = TokenType::Condition;
+            }
+        }
+    }
+}
+
+/// Post-pass: a string right after PIC/PICTURE is a picture specification
+fn classify_pictures(tokens: &mut [TokenRef<'_>]) {
+    let mut after_picture = false;
+    
+    for token in tokens.iter_mut() {
+        if token.token_type.is_trivia() {
+            continue;
+        }
+        if after_picture && token.token_type.is_string() {
+            token.token_type = TokenType::Picture;
+        }
+        after_picture = token.token_type == TokenType::KeywordType
+            && (token.text.eq_ignore_ascii_case("PIC") || token.text.eq_ignore_ascii_case("PICTURE"));
+    }
+}
+
+/// Post-pass: a name at the start of a statement (see `StatementStart`, so
+/// `A: B: PROC;` yields two labels) followed by `:` is a label. What follows
+/// the colon must be able to begin a statement: in `X : Y;` a lone name
+/// before the `;` cannot, so `X` stays an `Identifier`.
+fn classify_labels(tokens: &mut [TokenRef<'_>]) {
+    let mut statement = StatementStart::new();
+    
+    for i in 0..tokens.len() {
+        if tokens[i].token_type.is_trivia() {
+            continue;
+        }
+        let label_colon = next_significant(tokens, i, true).filter(|&j| tokens[j].text == ":" && colon_opens_statement(tokens, j));
+        if statement.at_start() && label_colon.is_some() && tokens[i].token_type.is_word() {
+            tokens[i].token_type = TokenType::Label;
+        }
+        statement.advance(&tokens[i]);
+    }
+}
+
+/// Whether the tokens after the `:` at `colon` can be the labelled statement.
+/// Anything but a bare identifier ending the statement (`X : Y;`) qualifies,
+/// including nothing yet, as while `MAIN:` is being typed.
+fn colon_opens_statement(tokens: &[TokenRef<'_>], colon: usize) -> bool {
+    match next_significant(tokens, colon, true) {
+        Some(name) if tokens[name].token_type == TokenType::Identifier => {
+            next_significant(tokens, name, true).is_some_and(|after| tokens[after].text != ";")
+        }
+        _ => true,
+    }
+}
+
+/// Optional analysis pass: names declared with DCL/DECLARE that spell a keyword
+/// or builtin (`DCL DATE FIXED BIN;`) are downgraded to `Identifier`, both in
+/// the declaration and wherever else they are used.
+///
+/// Heuristic limits: declarations are collected from the whole input, ignoring
+/// block scope, and a declared name at the start of a statement is only treated
+/// as a variable when an assignment `=` follows, so `DCL IF ...;` still leaves
+/// `IF X THEN` statements alone. Names declared via `%DCL` or implicitly are
+/// not considered.
+pub fn reclassify_declared_names(tokens: &mut [Token]) {
+    let significant: Vec<usize> = (0..tokens.len())
+        .filter(|&i| !tokens[i].token_type.is_trivia())
+        .collect();
+    let declarations = declared_names(tokens, &significant);
+    let shadowed: HashSet<String> = declarations
+        .iter()
+        .filter(|&&i| tokens[i].token_type != TokenType::Identifier)
+        .map(|&i| tokens[i].text.to_ascii_uppercase())
+        .collect();
+    if shadowed.is_empty() {
+        return;
+    }
+    
+    let mut statement = StatementStart::new();
+    let mut downgrade = Vec::new();
+    for &i in &significant {
+        let token = &tokens[i];
+        if token.token_type.is_word() && shadowed.contains(&token.text.to_ascii_uppercase()) {
+            let assigned = next_significant(tokens, i, true).is_some_and(|j| tokens[j].text == "=");
+            if !statement.at_start() || assigned || declarations.contains(&i) {
+                downgrade.push(i);
+            }
+        }
+        statement.advance(token);
+    }
+    
+    for i in downgrade {
+        tokens[i].token_type = TokenType::Identifier;
+        tokens[i].canonical = None;
+    }
+}
+
+/// Indices of the names introduced by DCL/DECLARE statements, including
+/// structure members (`DCL 1 S, 2 A;`) and factored lists (`DCL (A, B) FIXED;`)
+fn declared_names(tokens: &[Token], significant: &[usize]) -> Vec<usize> {
+    let mut names = Vec::new();
+    let mut in_declaration = false;
+    let mut statement = StatementStart::new();
+    let mut expect_name = false;
+    let mut depth = 0usize;
+    let mut factored_depth = None;
+    
+    for &i in significant {
+        let token = &tokens[i];
+        let at_statement_start = statement.at_start();
+        statement.advance(token);
+        if !in_declaration {
+            in_declaration = at_statement_start
+                && token.token_type.is_keyword()
+                && (token.text.eq_ignore_ascii_case("DCL") || token.text.eq_ignore_ascii_case("DECLARE"));
+            expect_name = in_declaration;
+            continue;
+        }
+        
+        match token.text.as_str() {
+            ";" => {
+                in_declaration = false;
+                depth = 0;
+                factored_depth = None;
+            }
+            "(" => {
+                depth += 1;
+                if expect_name && factored_depth.is_none() {
+                    factored_depth = Some(depth);
+                }
+            }
+            ")" => {
+                if factored_depth == Some(depth) {
+                    factored_depth = None;
+                    expect_name = false;
+                }
+                depth = depth.saturating_sub(1);
+            }
+            "," => expect_name = depth == 0 || factored_depth == Some(depth),
+            _ if token.token_type == TokenType::Number => {}
+            _ => {
+                if expect_name && token.token_type.is_word() {
+                    names.push(i);
+                }
+                expect_name = false;
+            }
+        }
+    }
+    
+    names
+}
+
+/// One name declared by a DCL/DECLARE statement. `start..end` is the name;
+/// `attributes` are the attribute texts as written (`FIXED`, `CHAR(10)`, a
+/// bare `(10)` dimension), with a factored list's shared attributes appended
+/// to each member's own.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeclareItem {
+    pub name: String,
+    pub level: Option<u32>,
+    pub attributes: Vec<String>,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// `declares` serialized as `{ name, level, attributes, start, end }[]`
+#[wasm_bindgen(js_name = declares)]
+pub fn declares_js(code: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&declares(code)).unwrap_or(JsValue::NULL)
+}
+
+/// Every item of every DCL/DECLARE statement in source order. Factored lists
+/// are expanded, so `DCL (A, B) FIXED BIN;` gives `A` and `B` each with
+/// `FIXED`, `BIN`; a level number before a factored list applies to all of
+/// its members (`DCL 1 REC, 2 (X, Y) CHAR(1);`).
+pub fn declares(code: &str) -> Vec<DeclareItem> {
+    let tokens: Vec<TokenRef<'_>> = tokenize_borrowed(code)
+        .into_iter()
+        .filter(|t| !t.token_type.is_trivia())
+        .collect();
+    let mut items = Vec::new();
+    let mut statement = StatementStart::new();
+    let mut i = 0;
+    
+    while i < tokens.len() {
+        let token = &tokens[i];
+        i += 1;
+        let is_declare = statement.at_start()
+            && token.token_type.is_keyword()
+            && (token.text.eq_ignore_ascii_case("DCL") || token.text.eq_ignore_ascii_case("DECLARE"));
+        statement.advance(token);
+        if !is_declare {
+            continue;
+        }
+        
+        items.extend(declare_list(code, &tokens, &mut i, None));
+        while i < tokens.len() && tokens[i].text != ";" {
+            i += 1;
+        }
+    }
+    
+    items
+}
+
+/// Comma-separated declare items starting at `tokens[*i]`, up to the `;` or
+/// `)` that ends the list
+fn declare_list(code: &str, tokens: &[TokenRef<'_>], i: &mut usize, level: Option<u32>) -> Vec<DeclareItem> {
+    let mut items = Vec::new();
+    loop {
+        items.extend(declare_item(code, tokens, i, level));
+        match tokens.get(*i) {
+            Some(t) if t.text == "," => *i += 1,
+            _ => return items,
+        }
+    }
+}
+
+/// `[level] (name | '(' list ')') attribute*`
+fn declare_item(code: &str, tokens: &[TokenRef<'_>], i: &mut usize, level: Option<u32>) -> Vec<DeclareItem> {
+    let mut level = level;
+    if let Some(number) = tokens.get(*i).filter(|t| t.token_type == TokenType::Number) {
+        level = number.text.parse().ok();
+        *i += 1;
+    }
+    
+    let mut items = match tokens.get(*i) {
+        Some(t) if t.text == "(" => {
+            *i += 1;
+            let items = declare_list(code, tokens, i, level);
+            if tokens.get(*i).is_some_and(|t| t.text == ")") {
+                *i += 1;
+            }
+            items
+        }
+        Some(t) if t.token_type.is_word() => {
+            *i += 1;
+            vec![DeclareItem { name: t.text.to_string(), level, attributes: Vec::new(), start: t.start, end: t.end }]
+        }
+        _ => Vec::new(),
+    };
+    
+    // Attributes run to the next `,`, `)` or `;` at this nesting level; a
+    // parenthesized group belongs to the word before it
+    let mut attributes = Vec::new();
+    while let Some(token) = tokens.get(*i).filter(|t| ![",", ")", ";"].contains(&t.text)) {
+        let start = token.start;
+        let mut end = token.end;
+        *i += 1;
+        if token.text == "(" || tokens.get(*i).is_some_and(|t| t.text == "(") {
+            let mut depth = 0usize;
+            let mut k = if token.text == "(" { *i - 1 } else { *i };
+            while let Some(t) = tokens.get(k) {
+                match t.text {
+                    "(" => depth += 1,
+                    ")" => depth -= 1,
+                    ";" => break,
+                    _ => {}
+                }
+                end = t.end;
+                k += 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            *i = k;
+        }
+        attributes.push(code[start..end].to_string());
+    }
+    
+    for item in &mut items {
+        item.attributes.extend(attributes.iter().cloned());
+    }
+    items
+}
+
+/// Optional analysis pass: for each token, whether it is an `=` that most
+/// likely assigns rather than compares. PL/I spells both the same way, so
+/// this is a heuristic: the first `=` outside parentheses in a statement is the
+/// assignment (including the loop variable of `DO I = 1 TO N`), unless
+/// the statement is an IF or WHEN condition; every other `=` compares.
+/// Statements start where `StatementStart` says.
+pub fn assignment_hints(tokens: &[Token]) -> Vec<bool> {
+    let mut hints = vec![false; tokens.len()];
+    let mut statement = StatementStart::new();
+    let mut assigned = false;
+    let mut depth = 0usize;
+    
+    for (i, token) in tokens.iter().enumerate() {
+        if token.token_type.is_trivia() {
+            continue;
+        }
+        if statement.at_start() {
+            let keyword_is = |word: &str| token.token_type.is_keyword() && token.text.eq_ignore_ascii_case(word);
+            assigned = keyword_is("WHEN") || keyword_is("IF");
+            depth = 0;
+        }
+        statement.advance(token);
+        
+        match token.text.as_str() {
+            "(" => depth += 1,
+            ")" => depth = depth.saturating_sub(1),
+            "=" if token.token_type == TokenType::Operator => {
+                hints[i] = depth == 0 && !assigned;
+                assigned = true;
+            }
+            _ => {}
+        }
+    }
+    
+    hints
+}
+
+/// Byte offsets of the `=` tokens `assignment_hints` marks as assignments, for
+/// themes that color them apart from comparisons
+#[wasm_bindgen]
+pub fn assignment_offsets(code: &str) -> Vec<u32> {
+    let tokens = tokenize(code);
+    assignment_hints(&tokens)
+        .into_iter()
+        .zip(&tokens)
+        .filter(|(is_assignment, _)| *is_assignment)
+        .map(|(_, token)| token.start as u32)
+        .collect()
+}
+
+/// Incremental tokenization - only re-tokenize changed region
+/// Returns tokens for the specified byte range, with raw lexer types: the
+/// window is lexed on its own, so the context post-passes don't run
+#[wasm_bindgen]
+pub fn tokenize_range(code: &str, start_byte: usize, end_byte: usize) -> Vec<u32> {
+    let (start, end) = line_bounds(code, start_byte, end_byte);
+    
+    let slice = &code[start..end];
+    let mut result = Vec::new();
+    let mut lexer = PLIToken::lexer(slice);
+    
+    while let Some((token_type, span)) = next_raw(&mut lexer) {
+        // Adjust offsets to original code position
+        result.push(token_type as u32);
+        result.push((start + span.start) as u32);
+        result.push((start + span.end) as u32);
+    }
+    
+    result
+}
+
+/// Result of `tokenize_range_safe`: flat tokens for the re-lexed window
+#[wasm_bindgen]
+#[derive(Debug, Clone)]
+pub struct RangeTokens {
+    tokens: Vec<u32>,
+    start: usize,
+    end: usize,
+    tail_invalidated: bool,
+}
+
+#[wasm_bindgen]
+impl RangeTokens {
+    /// Flat [type, start, end, ...] tokens covering `start..end`
+    #[wasm_bindgen(getter)]
+    pub fn tokens(&self) -> Vec<u32> {
+        self.tokens.clone()
+    }
+    
+    /// First byte of the re-lexed window
+    #[wasm_bindgen(getter)]
+    pub fn start(&self) -> usize {
+        self.start
+    }
+    
+    /// End of the re-lexed window (exclusive)
+    #[wasm_bindgen(getter)]
+    pub fn end(&self) -> usize {
+        self.end
+    }
+    
+    /// True when the window began or ended inside a multi-line comment or
+    /// string, so highlighting below the window may have changed as well
+    #[wasm_bindgen(getter)]
+    pub fn tail_invalidated(&self) -> bool {
+        self.tail_invalidated
+    }
+}
+
+/// Like `tokenize_range`, but aware of multi-line comment/string state. If the
+/// first changed line starts inside such a token, the window is extended back
+/// to its start; if a token runs past the last changed line, lexing continues
+/// to the first line boundary after it. Either case sets `tail_invalidated`.
+/// As with `tokenize_range`, types are the raw lexer's.
+#[wasm_bindgen]
+pub fn tokenize_range_safe(code: &str, start_byte: usize, end_byte: usize) -> RangeTokens {
+    let (window_start, window_end) = line_bounds(code, start_byte, end_byte);
+    let mut tokens = Vec::new();
+    let mut start = window_start;
+    let mut end = window_start;
+    let mut after_newline = false;
+    let mut lexer = PLIToken::lexer(code);
+    
+    while let Some((token_type, span)) = next_raw(&mut lexer) {
+        if span.end <= window_start {
+            continue;
+        }
+        if tokens.is_empty() {
+            start = span.start;
+        }
+        // Beyond the window the lexer state is stable again at a line boundary
+        if span.start >= window_end && (end <= window_end || after_newline) {
+            break;
+        }
+        
+        tokens.push(token_type as u32);
+        tokens.push(span.start as u32);
+        tokens.push(span.end as u32);
+        end = span.end;
+        after_newline = token_type == TokenType::Newline;
+    }
+    
+    RangeTokens { tokens, start, end, tail_invalidated: start < window_start || end > window_end }
+}
+
+/// Expand a byte range to whole lines (the end includes its trailing newline).
+/// Offsets past the end or inside a multi-byte char are snapped first, so any
+/// caller-supplied range is safe to slice with.
+fn line_bounds(code: &str, start_byte: usize, end_byte: usize) -> (usize, usize) {
+    let start_byte = snap_to_char_boundary(code, start_byte);
+    let end_byte = snap_to_char_boundary(code, end_byte).max(start_byte);
+    // An offset between the CR and LF of a CRLF is still on the CR's line
+    let mut before = &code[..start_byte];
+    if before.ends_with('\r') && code[start_byte..].starts_with('\n') {
+        before = &before[..before.len() - 1];
+    }
+    let start = before.rfind(['\n', '\r']).map(|i| i + 1).unwrap_or(0);
+    let end = match code[end_byte..].find(['\n', '\r']) {
+        Some(i) if code[end_byte + i..].starts_with("\r\n") => end_byte + i + 2,
+        Some(i) => end_byte + i + 1,
+        None => code.len(),
+    };
+    (start, end)
+}
+
+/// Tokens of one line (0-based `line_index`, including its line terminator)
+/// as flat [type, start, end, ...] triples with file offsets. Lexing starts at
+/// the top of the file, so a line inside a multi-line comment or string is
+/// classified exactly; tokens running across the line's edges are clipped to
+/// it. Past the last line the result is empty. Types are lexical - a label on
+/// the line is reported as `Identifier`.
+#[wasm_bindgen]
+pub fn tokenize_line(code: &str, line_index: usize) -> Vec<u32> {
+    let mut lines = split_lines_inclusive(code);
+    let line_start: usize = lines.by_ref().take(line_index).map(str::len).sum();
+    let Some(line) = lines.next() else { return Vec::new() };
+    let line_end = line_start + line.len();
+    
+    let mut result = Vec::new();
+    let mut lexer = PLIToken::lexer(code);
+    
+    while let Some((token_type, span)) = next_raw(&mut lexer) {
+        if span.end <= line_start {
+            continue;
+        }
+        if span.start >= line_end {
+            break;
+        }
+        result.push(token_type as u32);
+        result.push(span.start.max(line_start) as u32);
+        result.push(span.end.min(line_end) as u32);
+    }
+    
+    result
+}
+
+/// `idx` clamped to `code.len()` and moved back to the start of the char it
+/// falls inside, the nearest offset at or before it that is safe to slice at
+#[wasm_bindgen]
+pub fn snap_to_char_boundary(code: &str, idx: usize) -> usize {
+    let mut idx = idx.min(code.len());
+    while !code.is_char_boundary(idx) {
+        idx -= 1;
+    }
+    idx
+}
+
+/// `line_text` for JS: undefined past the last line
+#[wasm_bindgen(js_name = line_text)]
+pub fn line_text_js(code: &str, line_index: usize) -> Option<String> {
+    line_text(code, line_index).map(str::to_string)
+}
+
+/// Text of 0-based line `line_index` without its line break (LF, CRLF or
+/// lone CR), or `None` past the last line. After a final line break there is
+/// one more, empty line, as in `tokens_by_line`.
+pub fn line_text(code: &str, line_index: usize) -> Option<&str> {
+    if line_index == line_break_count(code) && (code.is_empty() || code.ends_with(['\n', '\r'])) {
+        return Some("");
+    }
+    let line = split_lines_inclusive(code).nth(line_index)?;
+    Some(line.trim_end_matches(['\n', '\r']))
+}
+
+/// 0-based line of byte `offset` (clamped to the end of `code`). A line break
+/// belongs to the line it ends, so the `\n` of a CRLF is on the line of its `\r`.
+#[wasm_bindgen]
+pub fn line_of_offset(code: &str, offset: usize) -> usize {
+    let offset = snap_to_char_boundary(code, offset);
+    let line = line_break_count(&code[..offset]);
+    if code[..offset].ends_with('\r') && code[offset..].starts_with('\n') {
+        line - 1
+    } else {
+        line
+    }
+}
+
+/// What the chunked tokenizer was in the middle of when it stopped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum ChunkMode {
+    #[default]
+    Between,
+    Comment,
+    String(u8),
+}
+
+/// Resumable position for `tokenize_chunk`. Start from `LexerState::new()`;
+/// each call returns the state to pass to the next one.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LexerState {
+    offset: usize,
+    scanned: usize,
+    mode: ChunkMode,
+}
+
+#[wasm_bindgen]
+impl LexerState {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> LexerState {
+        LexerState::default()
+    }
+    
+    /// Start of the next token to be emitted
+    #[wasm_bindgen(getter)]
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+    
+    /// True when the last chunk stopped inside a `/* */` comment
+    #[wasm_bindgen(getter)]
+    pub fn in_comment(&self) -> bool {
+        self.mode == ChunkMode::Comment
+    }
+    
+    /// True when the last chunk stopped inside a quoted literal
+    #[wasm_bindgen(getter)]
+    pub fn in_string(&self) -> bool {
+        matches!(self.mode, ChunkMode::String(_))
+    }
+}
+
+/// Result of `tokenize_chunk` for JavaScript, which has no tuples
+#[wasm_bindgen]
+#[derive(Debug, Clone)]
+pub struct LexerChunk {
+    tokens: Vec<u32>,
+    state: LexerState,
+    done: bool,
+}
+
+#[wasm_bindgen]
+impl LexerChunk {
+    /// Flat [type, start, end, ...] tokens completed by this chunk
+    #[wasm_bindgen(getter)]
+    pub fn tokens(&self) -> Vec<u32> {
+        self.tokens.clone()
+    }
+    
+    /// State to pass to the next call
+    #[wasm_bindgen(getter)]
+    pub fn state(&self) -> LexerState {
+        self.state
+    }
+    
+    /// True once the whole input has been tokenized
+    #[wasm_bindgen(getter)]
+    pub fn done(&self) -> bool {
+        self.done
+    }
+}
+
+/// `tokenize_chunk` returning a `LexerChunk`
+#[wasm_bindgen(js_name = tokenize_chunk)]
+pub fn tokenize_chunk_js(code: &str, state: LexerState, byte_limit: usize) -> LexerChunk {
+    let (tokens, state) = tokenize_chunk(code, state, byte_limit);
+    LexerChunk { tokens, state, done: state.offset >= code.len() }
+}
+
+/// Tokenize roughly `byte_limit` bytes of `code`, resuming from `state`.
+/// Returns the completed tokens (flat, as `tokenize_flat`) and the state for
+/// the next call; concatenating every chunk gives the raw lexer stream - that
+/// is `tokenize_flat(code)` before its context post-passes, which would need
+/// tokens beyond the chunk. A comment or string that crosses the limit is
+/// scanned across calls and emitted whole by the chunk in which it ends.
+pub fn tokenize_chunk(code: &str, state: LexerState, byte_limit: usize) -> (Vec<u32>, LexerState) {
+    let bytes = code.as_bytes();
+    let LexerState { offset, mut scanned, mut mode } = state;
+    let resume = if mode == ChunkMode::Between { offset } else { scanned };
+    let limit = resume.saturating_add(byte_limit.max(1)).min(code.len());
+    let mut result = Vec::new();
+    let mut lexer = PLIToken::lexer(code);
+    lexer.bump(offset);
+    
+    loop {
+        // Find where a pending comment/string ends before letting the lexer at it
+        while mode != ChunkMode::Between && scanned < code.len() {
+            if scanned >= limit {
+                return (result, LexerState { offset: lexer.span().end, scanned, mode });
+            }
+            match mode {
+                ChunkMode::Comment if bytes[scanned] == b'*' && bytes.get(scanned + 1) == Some(&b'/') => {
+                    mode = ChunkMode::Between;
+                }
+                ChunkMode::String(quote) if bytes[scanned] == quote => {
+                    if bytes.get(scanned + 1) == Some(&quote) {
+                        scanned += 1;
+                    } else {
+                        mode = ChunkMode::Between;
+                    }
+                }
+                _ => {}
+            }
+            scanned += 1;
+        }
+        mode = ChunkMode::Between;
+        
+        let start = lexer.span().end;
+        if start >= limit && scanned <= start {
+            break;
+        }
+        let rest = lexer.remainder();
+        if scanned <= start {
+            if rest.starts_with("/*") {
+                mode = ChunkMode::Comment;
+                scanned = start + 2;
+                continue;
+            }
+            if let Some(quote) = rest.bytes().next().filter(|b| *b == b'\'' || *b == b'"') {
+                mode = ChunkMode::String(quote);
+                scanned = start + 1;
+                continue;
+            }
+        }
+        
+        let Some((token_type, span)) = next_raw(&mut lexer) else { break };
+        result.push(token_type as u32);
+        result.push(span.start as u32);
+        result.push(span.end as u32);
+    }
+    
+    let offset = lexer.span().end;
+    (result, LexerState { offset, scanned: offset, mode: ChunkMode::Between })
+}
+
+/// Token delta produced by an edit: old tokens `first..last` are replaced by
+/// `tokens` (positioned in the new code). Old tokens from `last` onward are
+/// unchanged apart from shifting by `byte_delta` bytes and `line_delta` lines.
+/// A no-op edit yields `first == last` and no tokens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenEdit {
+    pub first: usize,
+    pub last: usize,
+    pub tokens: Vec<Token>,
+    pub byte_delta: isize,
+    pub line_delta: isize,
+}
+
+/// `retokenize` serialized as `{ first, last, tokens, byteDelta, lineDelta }`
+#[wasm_bindgen(js_name = retokenize)]
+pub fn retokenize_js(old_code: &str, new_code: &str, change_start: usize, change_len_old: usize, change_len_new: usize) -> JsValue {
+    let edit = retokenize(old_code, new_code, change_start, change_len_old, change_len_new);
+    serde_wasm_bindgen::to_value(&edit).unwrap_or(JsValue::NULL)
+}
+
+/// Compute the token delta for replacing `change_len_old` bytes at
+/// `change_start` of `old_code` with `change_len_new` bytes, giving `new_code`.
+/// Only the code from the last statement boundary (a `;` outside parentheses)
+/// before the edited line is lexed and post-processed again: the post-passes
+/// never look across such a `;`, but within a statement they may reclassify
+/// tokens on earlier lines (`SUBSTR(...)` becoming a pseudovariable once `=`
+/// follows). The dirty region ends where the token streams resynchronize,
+/// which may be well past the edit when it opens or closes a multi-line
+/// comment or string.
+pub fn retokenize(old_code: &str, new_code: &str, change_start: usize, change_len_old: usize, change_len_new: usize) -> TokenEdit {
+    let byte_delta = change_len_new as isize - change_len_old as isize;
+    let old_change_end = change_start + change_len_old;
+    let (line_start, _) = line_bounds(old_code, change_start, change_start);
+    
+    // Both documents agree up to the edited line, so the restart point and the
+    // tokens before it come from a raw scan of the old one
+    let mut restart = Restart::default();
+    let mut stream = TokenStream::new(old_code);
+    let mut depth = 0usize;
+    let mut count = 0;
+    while let Some(token) = stream.next_ref().filter(|t| t.end <= line_start) {
+        count += 1;
+        if token.token_type != TokenType::Punctuation {
+            continue;
+        }
+        match token.text {
+            "(" => depth += 1,
+            ")" => depth = depth.saturating_sub(1),
+            ";" if depth == 0 => restart = Restart { offset: token.end, tokens: count, line: token.line, column: token.column + 1 },
+            _ => {}
+        }
+    }
+    
+    let old = restart.tokenize(old_code);
+    let new = restart.tokenize(new_code);
+    let mut first = 0;
+    while first < old.len() && first < new.len() && same_token(&old[first], &new[first], 0) {
+        first += 1;
+    }
+    
+    // Walk back from the end while the streams agree after shifting
+    let mut suffix = 0;
+    while suffix < old.len() - first && suffix < new.len() - first {
+        let o = &old[old.len() - 1 - suffix];
+        let n = &new[new.len() - 1 - suffix];
+        if o.start < old_change_end || !same_token(o, n, byte_delta) {
+            break;
+        }
+        suffix += 1;
+    }
+    
+    let line_delta = line_break_count(new_code) as isize - line_break_count(old_code) as isize;
+    TokenEdit {
+        first: restart.tokens + first,
+        last: restart.tokens + old.len() - suffix,
+        tokens: new[first..new.len() - suffix].iter().copied().map(Token::from).collect(),
+        byte_delta,
+        line_delta,
+    }
+}
+
+/// Where `retokenize` resumes lexing: just after a `;` at `line`/`column`,
+/// preceded by `tokens` tokens. The default is the start of the input.
+#[derive(Default)]
+struct Restart {
+    offset: usize,
+    tokens: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Restart {
+    /// `code` from the restart point on, positioned as if lexed from the start
+    fn tokenize<'a>(&self, code: &'a str) -> Vec<TokenRef<'a>> {
+        let mut tokens = tokenize_borrowed(&code[self.offset..]);
+        for token in &mut tokens {
+            if token.line == 0 {
+                token.column += self.column;
+            }
+            token.line += self.line;
+            token.start += self.offset;
+            token.end += self.offset;
+        }
+        tokens
+    }
+}
+
+fn same_token(old: &TokenRef<'_>, new: &TokenRef<'_>, byte_delta: isize) -> bool {
+    old.token_type == new.token_type
+        && old.text == new.text
+        && old.start as isize + byte_delta == new.start as isize
+}
+
+/// Fixed-format (card image) tokenization. Everything from the 1-based column
+/// `seq_start_col` to end-of-line is emitted as `SequenceNumber`; shorter lines
+/// lex normally. Returns the same flat layout as `tokenize_flat`, with raw
+/// lexer types (no context post-passes).
+#[wasm_bindgen]
+pub fn tokenize_fixed(code: &str, seq_start_col: usize) -> Vec<u32> {
+    tokenize_flat_with_options(code, &fixed_options(seq_start_col))
+}
+
+/// Column layout of the source. Margins are 1-based inclusive char columns
+/// delimiting the significant region of every line; the defaults (1 and
+/// `usize::MAX`) are plain free-format source.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// First significant column; earlier columns are lexed as `Whitespace`
+    pub left_margin: usize,
+    /// Last significant column; later columns are lexed as `SequenceNumber`
+    pub right_margin: usize,
+    /// Tab stop width used for margins and reported columns; 0 or 1 counts a
+    /// tab as a single column
+    pub tab_width: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions { left_margin: 1, right_margin: usize::MAX, tab_width: 1 }
+    }
+}
+
+#[wasm_bindgen]
+impl FormatOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> FormatOptions {
+        FormatOptions::default()
+    }
+    
+    /// Classic card layout: columns 2-72 significant, 1 is carriage control
+    pub fn card() -> FormatOptions {
+        FormatOptions { left_margin: 2, right_margin: 72, tab_width: 1 }
+    }
+}
+
+/// Tokenize with an explicit column layout, flat layout as `tokenize_flat`.
+/// Types are lexical; `tokenize_with_options` applies the post-passes.
+#[wasm_bindgen]
+pub fn tokenize_flat_with_options(code: &str, options: &FormatOptions) -> Vec<u32> {
+    let regions = margin_regions(code, options);
+    let mut result = Vec::with_capacity(estimated_tokens(code) * 3);
+    
+    for (token_type, span) in lex_masked(code, &regions, LexOptions::default()) {
+        result.push(token_type as u32);
+        result.push(span.start as u32);
+        result.push(span.end as u32);
+    }
+    
+    result
+}
+
+/// Tokenize with an explicit column layout, returning Token structs
+pub fn tokenize_with_options(code: &str, options: &FormatOptions) -> Vec<Token> {
+    let regions = margin_regions(code, options);
+    build_tokens(code, lex_masked(code, &regions, LexOptions::default()), options.tab_width)
+}
+
+/// `tokenize_fixed` returning Token structs
+pub fn tokenize_fixed_tokens(code: &str, seq_start_col: usize) -> Vec<Token> {
+    tokenize_with_options(code, &fixed_options(seq_start_col))
+}
+
+fn fixed_options(seq_start_col: usize) -> FormatOptions {
+    FormatOptions { left_margin: 1, right_margin: seq_start_col.saturating_sub(1), tab_width: 1 }
+}
+
+/// Turn raw (type, span) pairs into Token structs with positions and labels
+fn build_tokens(code: &str, raw: Vec<(TokenType, Range<usize>)>, tab_width: usize) -> Vec<Token> {
+    build_refs(code, raw, tab_width).into_iter().map(Token::from).collect()
+}
+
+/// Positioned, post-processed tokens from `lex_masked` output
+fn build_refs(code: &str, raw: Vec<(TokenType, Range<usize>)>, tab_width: usize) -> Vec<TokenRef<'_>> {
+    let mut tokens = Vec::with_capacity(raw.len());
+    let mut pos = LineTracker::with_tab_width(tab_width);
+    
+    for (token_type, span) in raw {
+        let slice = &code[span.clone()];
+        tokens.push(TokenRef {
+            text: slice,
+            token_type,
+            start: span.start,
+            end: span.end,
+            line: pos.line,
+            column: pos.column,
+        });
+        pos.advance(slice);
+    }
+    
+    post_process(&mut tokens);
+    tokens
+}
+
+/// Byte ranges outside the significant columns of each line, paired with the
+/// token type they are reported as. Margins are 1-based inclusive char columns:
+/// columns before `left_margin` are `Whitespace`, columns after `right_margin`
+/// are `SequenceNumber`. Line terminators are never part of a margin. A tab
+/// spans up to its tab stop and falls on the side of the margin it starts on.
+fn margin_regions(code: &str, options: &FormatOptions) -> Vec<(Range<usize>, TokenType)> {
+    let mut regions = Vec::new();
+    let mut line_start = 0;
+    let left_col = options.left_margin.saturating_sub(1);
+    let right_col = options.right_margin.max(left_col);
+    
+    for line in split_lines_inclusive(code) {
+        let content = line.trim_end_matches(['\n', '\r']);
+        // Byte offsets of the first chars starting at or past each margin
+        let (mut left, mut right) = (content.len(), content.len());
+        let mut column = 0;
+        for (i, c) in content.char_indices() {
+            if column >= left_col && left == content.len() {
+                left = i;
+            }
+            if column >= right_col {
+                right = i;
+                break;
+            }
+            column = advance_column(column, &content[i..i + c.len_utf8()], options.tab_width);
+        }
+        let left = left.min(right);
+        
+        if left > 0 {
+            regions.push((line_start..line_start + left, TokenType::Whitespace));
+        }
+        if right < content.len() {
+            regions.push((line_start + right..line_start + content.len(), TokenType::SequenceNumber));
+        }
+        
+        line_start += line.len();
+    }
+    
+    regions
+}
+
+/// Lex `code` with the given regions blanked out, then carve the regions back
+/// out of whatever tokens cover them. Blanking keeps comments and strings that
+/// run through a margin intact on either side of it.
+fn lex_masked(code: &str, regions: &[(Range<usize>, TokenType)], options: LexOptions) -> Vec<(TokenType, Range<usize>)> {
+    let masked;
+    let source = if regions.is_empty() {
+        code
+    } else {
+        let mut bytes = code.as_bytes().to_vec();
+        for (range, _) in regions {
+            bytes[range.clone()].fill(b' ');
+        }
+        // Regions are char aligned, so only whole chars were replaced
+        masked = String::from_utf8(bytes).unwrap_or_default();
+        masked.as_str()
+    };
+    
+    let mut result: Vec<(TokenType, Range<usize>)> = Vec::new();
+    let mut stream = TokenStream::with_options(source, options);
+    let mut next_region = 0;
+    
+    while let Some(token) = stream.next_ref() {
+        let (token_type, span) = (token.token_type, token.start..token.end);
+        
+        let mut cursor = span.start;
+        while cursor < span.end {
+            while next_region < regions.len() && regions[next_region].0.end <= cursor {
+                next_region += 1;
+            }
+            match regions.get(next_region) {
+                Some((range, kind)) if range.start < span.end => {
+                    if range.start > cursor {
+                        result.push((token_type, cursor..range.start));
+                    }
+                    let piece = range.start.max(cursor)..range.end.min(span.end);
+                    match result.last_mut() {
+                        Some((last_type, last)) if *last_type == *kind && last.end == piece.start => {
+                            last.end = piece.end;
+                        }
+                        _ => result.push((*kind, piece.clone())),
+                    }
+                    cursor = piece.end;
+                }
+                _ => {
+                    result.push((token_type, cursor..span.end));
+                    cursor = span.end;
+                }
+            }
+        }
+    }
+    
+    result
+}
+
+/// Tokenizer configured once and reused: extra keywords, column layout and
+/// dialect switches. The keyword set is built by the constructor instead of on
+/// every call. Results are post-processed like `tokenize` (labels, pictures,
+/// conditions) in the flat layout of `tokenize_flat`.
+#[wasm_bindgen]
+#[derive(Debug, Clone)]
+pub struct Highlighter {
+    keywords: HashSet<String>,
+    format: FormatOptions,
+    options: LexOptions,
+    /// Output of `tokenize_into`, kept between calls so its capacity is reused
+    buffer: Vec<u32>,
+}
+
+#[wasm_bindgen]
+impl Highlighter {
+    #[wasm_bindgen(constructor)]
+    pub fn new(extra_keywords: Vec<String>, format: &FormatOptions, options: &LexOptions) -> Highlighter {
+        Highlighter { keywords: keyword_set(&extra_keywords), format: *format, options: *options, buffer: Vec::new() }
+    }
+    
+    /// Whole-document tokens as [type, start, end, ...]
+    pub fn tokenize_flat(&self, code: &str) -> Vec<u32> {
+        flatten(&self.tokenize_refs(code), 0)
+    }
+    
+    /// `tokenize_flat` into the highlighter's own buffer, which is cleared but
+    /// not freed between calls, so a keystroke loop stops allocating output
+    /// once the buffer has grown to fit the document. Returns the number of
+    /// `u32`s written; read them through `buffer_ptr` as
+    /// `new Uint32Array(memory.buffer, buffer_ptr(), length)`. The view is only
+    /// valid until the next call into the module.
+    pub fn tokenize_into(&mut self, code: &str) -> usize {
+        let tokens = self.tokenize_refs(code);
+        self.buffer.clear();
+        self.buffer.extend(tokens.iter().flat_map(|t| [t.token_type as u32, t.start as u32, t.end as u32]));
+        self.buffer.len()
+    }
+    
+    /// Address of the `tokenize_into` output in wasm memory
+    pub fn buffer_ptr(&self) -> *const u32 {
+        self.buffer.as_ptr()
+    }
+    
+    /// Number of `u32`s last written by `tokenize_into`
+    pub fn buffer_len(&self) -> usize {
+        self.buffer.len()
+    }
+    
+    /// Tokens for the lines touched by `start_byte..end_byte`, like the free
+    /// `tokenize_range`; offsets are positions in `code`
+    pub fn tokenize_range(&self, code: &str, start_byte: usize, end_byte: usize) -> Vec<u32> {
+        let (start, end) = line_bounds(code, start_byte, end_byte);
+        flatten(&self.tokenize_refs(&code[start..end]), start)
+    }
+    
+    /// `stats` serialized as `{ totalTokens, totalBytes, histogram }`
+    #[wasm_bindgen(js_name = stats)]
+    pub fn stats_js(&self, code: &str) -> JsValue {
+        let serializer = serde_wasm_bindgen::Serializer::new().serialize_maps_as_objects(true);
+        self.stats(code).serialize(&serializer).unwrap_or(JsValue::NULL)
+    }
+}
+
+impl Highlighter {
+    /// Token counts with this configuration applied
+    pub fn stats(&self, code: &str) -> TokenStats {
+        let mut stats = TokenStats::default();
+        for token in self.tokenize_refs(code) {
+            stats.record(&token);
+        }
+        stats
+    }
+    
+    /// `Token` structs with this configuration applied
+    pub fn tokenize(&self, code: &str) -> Vec<Token> {
+        self.tokenize_refs(code).into_iter().map(Token::from).collect()
+    }
+    
+    fn tokenize_refs<'a>(&self, code: &'a str) -> Vec<TokenRef<'a>> {
+        let regions = margin_regions(code, &self.format);
+        let mut tokens = build_refs(code, lex_masked(code, &regions, self.options), self.format.tab_width);
+        if self.options.context_sensitive_io {
+            classify_io_options(&mut tokens);
+        }
+        upgrade_keywords(&mut tokens, &self.keywords);
+        tokens
+    }
+}
+
+fn flatten(tokens: &[TokenRef<'_>], offset: usize) -> Vec<u32> {
+    let mut result = Vec::with_capacity(tokens.len() * 3);
+    result.extend(tokens.iter().flat_map(|t| [t.token_type as u32, (offset + t.start) as u32, (offset + t.end) as u32]));
+    result
+}
+
+/// How serious a diagnostic is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in the source, spanning bytes `start..end`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub start: usize,
+    pub end: usize,
+    pub message: String,
+    pub severity: Severity,
+}
+
+/// Diagnostics serialized as `{ start, end, message, severity }[]`
+#[wasm_bindgen(js_name = diagnostics)]
+pub fn diagnostics_js(code: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&diagnostics(code)).unwrap_or(JsValue::NULL)
+}
+
+/// Unterminated string and comment literals. A closed string always lexes as
+/// one token, so an Unknown token starting with a quote means the opener ran to
+/// end of input, as does a `Comment` without its closing `*/`; the diagnostic
+/// spans to the end of `code`.
+pub fn diagnostics(code: &str) -> Vec<Diagnostic> {
+    let mut result = Vec::new();
+    
+    for token in tokenize_borrowed(code) {
+        let message = match token.token_type {
+            TokenType::Unknown if token.text.starts_with('\'') || token.text.starts_with('"') => {
+                "Unterminated string literal"
+            }
+            TokenType::Comment if token.text.len() < 4 || !token.text.ends_with("*/") => "Unterminated comment",
+            _ => continue,
+        };
+        
+        result.push(Diagnostic {
+            start: token.start,
+            end: code.len(),
+            message: message.to_string(),
+            severity: Severity::Error,
+        });
+        // Everything after the opener belongs to it
+        break;
+    }
+    
+    result
+}
+
+/// `token_at` serialized as a `Token` object, or null
+#[wasm_bindgen(js_name = token_at)]
+pub fn token_at_js(code: &str, offset: usize) -> JsValue {
+    match token_at(code, offset) {
+        Some(token) => serde_wasm_bindgen::to_value(&token).unwrap_or(JsValue::NULL),
+        None => JsValue::NULL,
+    }
+}
+
+/// The token under byte `offset`: spans are half-open, so at the boundary
+/// between two tokens the one starting there wins (`offset == len` is past
+/// the end and gives `None`). Post-passes apply, so a label reports `Label`.
+pub fn token_at(code: &str, offset: usize) -> Option<Token> {
+    let tokens = tokenize_borrowed(code);
+    let index = tokens.partition_point(|t| t.end <= offset);
+    tokens.into_iter().nth(index).filter(|t| t.start <= offset).map(Token::from)
+}
+
+/// `first_error` as `[start, end]`, or undefined for clean input
+#[wasm_bindgen(js_name = first_error)]
+pub fn first_error_js(code: &str) -> Option<Vec<u32>> {
+    first_error(code).map(|(start, end)| vec![start as u32, end as u32])
+}
+
+/// Byte span of the first input no lexer rule matches, or `None` when the
+/// whole source lexes: a quick "is this PL/I at all?" check. The span is
+/// that of the `Unknown` token `tokenize` reports, covering the whole run
+/// of unmatched characters and always ending on a char boundary.
+pub fn first_error(code: &str) -> Option<(usize, usize)> {
+    let mut lexer = PLIToken::lexer(code);
+    std::iter::from_fn(|| next_raw(&mut lexer))
+        .find(|(token_type, _)| *token_type == TokenType::Unknown)
+        .map(|(_, span)| (span.start, span.end))
+}
+
+/// True when `code` holds nothing but whitespace, newlines and comments - a
+/// blank or comment-only line or file. Lexing stops at the first other token.
+#[wasm_bindgen]
+pub fn is_blank_or_comment(code: &str) -> bool {
+    let mut lexer = PLIToken::lexer(code);
+    std::iter::from_fn(|| next_raw(&mut lexer)).all(|(token_type, _)| token_type.is_trivia())
+}
+
+/// `tokenize` with the parenthesis depth at each token. An opening `(` and its
+/// closing `)` share the depth of their contents: `A(B)` gives A 0, `(` 1,
+/// B 1, `)` 1. Brackets inside strings and comments are not counted, and a
+/// stray `)` never takes the depth below 0.
+pub fn tokenize_with_depth(code: &str) -> Vec<(Token, u32)> {
+    let tokens = tokenize_borrowed(code);
+    let depths = depths_of(&tokens);
+    tokens.into_iter().map(Token::from).zip(depths).collect()
+}
+
+/// Parenthesis depth per token (see `tokenize_with_depth`), parallel to the
+/// records of `tokenize_flat`
+#[wasm_bindgen]
+pub fn paren_depths(code: &str) -> Vec<u32> {
+    depths_of(&tokenize_borrowed(code))
+}
+
+fn depths_of(tokens: &[TokenRef<'_>]) -> Vec<u32> {
+    let mut depth = 0u32;
+    tokens
+        .iter()
+        .map(|token| {
+            let is_bracket = token.token_type == TokenType::Punctuation;
+            match token.text {
+                "(" if is_bracket => {
+                    depth += 1;
+                    depth
+                }
+                ")" if is_bracket => {
+                    let current = depth;
+                    depth = depth.saturating_sub(1);
+                    current
+                }
+                _ => depth,
+            }
+        })
+        .collect()
+}
+
+/// Byte offset of the partner of the bracket or block keyword at `byte_offset`:
+/// `(`/`)`, `[`/`]`, and DO/BEGIN/SELECT/PROC/PROCEDURE with their END.
+/// Returns undefined when the offset is not on a matchable token or the
+/// partner is missing.
+#[wasm_bindgen]
+pub fn find_matching_bracket(code: &str, byte_offset: usize) -> Option<usize> {
+    let tokens = tokenize_borrowed(code);
+    let index = tokens.iter().position(|t| t.start <= byte_offset && byte_offset < t.end)?;
+    pair_table(&tokens)[index].map(|partner| tokens[partner].start)
+}
+
+/// An unbalanced block keyword: `start..end` is the opener without an END, or
+/// the END without an opener. `line` is 0-based; `message` counts from 1.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BalanceError {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub message: String,
+}
+
+/// `check_block_balance` serialized as `{ start, end, line, message }[]`
+#[wasm_bindgen(js_name = check_block_balance)]
+pub fn check_block_balance_js(code: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&check_block_balance(code)).unwrap_or(JsValue::NULL)
+}
+
+/// DO/BEGIN/SELECT/PROC openers still open at end of input and ENDs with
+/// nothing to close, in source order. `END label;` closing several blocks at
+/// once is balanced.
+pub fn check_block_balance(code: &str) -> Vec<BalanceError> {
+    let tokens = tokenize_borrowed(code);
+    let partners = pair_table(&tokens);
+    
+    tokens
+        .iter()
+        .zip(&partners)
+        .filter(|(token, partner)| partner.is_none() && (is_block_opener(token) || is_block_end(token)))
+        .map(|(token, _)| {
+            let message = if is_block_end(token) {
+                format!("END at line {} has no matching DO, BEGIN, SELECT or PROC", token.line + 1)
+            } else {
+                format!("missing END for {} at line {}", token.text.to_ascii_uppercase(), token.line + 1)
+            };
+            BalanceError { start: token.start, end: token.end, line: token.line, message }
+        })
+        .collect()
+}
+
+fn is_block_opener(token: &TokenRef<'_>) -> bool {
+    token.token_type == TokenType::KeywordControl
+        && ["DO", "BEGIN", "SELECT", "PROC", "PROCEDURE"]
+            .iter()
+            .any(|kw| token.text.eq_ignore_ascii_case(kw))
+}
+
+fn is_block_end(token: &TokenRef<'_>) -> bool {
+    token.token_type == TokenType::KeywordControl && token.text.eq_ignore_ascii_case("END")
+}
+
+/// For every token index, the index of its matching bracket or block keyword.
+/// `END name;` closes the block labelled `name` together with any blocks still
+/// open inside it (PL/I multiple closure); the inner openers point at that END,
+/// while the END itself points back at the labelled opener.
+fn pair_table(tokens: &[TokenRef<'_>]) -> Vec<Option<usize>> {
+    let mut partners = vec![None; tokens.len()];
+    let mut brackets: Vec<usize> = Vec::new();
+    // Open blocks with the labels written in front of them
+    let mut blocks: Vec<(usize, Vec<&str>)> = Vec::new();
+    let mut pending_labels: Vec<&str> = Vec::new();
+    
+    let significant: Vec<usize> = (0..tokens.len())
+        .filter(|&i| !tokens[i].token_type.is_trivia())
+        .collect();
+    
+    for (k, &i) in significant.iter().enumerate() {
+        let token = &tokens[i];
+        match token.text {
+            "(" | "[" => brackets.push(i),
+            ")" | "]" => {
+                let open = if token.text == ")" { "(" } else { "[" };
+                if let Some(&j) = brackets.last().filter(|&&j| tokens[j].text == open) {
+                    brackets.pop();
+                    partners[i] = Some(j);
+                    partners[j] = Some(i);
+                }
+            }
+            _ if is_block_opener(token) => {
+                blocks.push((i, std::mem::take(&mut pending_labels)));
+            }
+            _ if is_block_end(token) => {
+                let name = significant
+                    .get(k + 1)
+                    .map(|&j| &tokens[j])
+                    .filter(|t| t.token_type.is_word() || t.token_type == TokenType::Label)
+                    .map(|t| t.text);
+                let target = name.and_then(|name| {
+                    blocks
+                        .iter()
+                        .rposition(|(_, labels)| labels.iter().any(|l| l.eq_ignore_ascii_case(name)))
+                });
+                let depth = match target {
+                    Some(depth) => depth,
+                    None if blocks.is_empty() => continue,
+                    None => blocks.len() - 1,
+                };
+                for (opener, _) in blocks.drain(depth..).rev() {
+                    partners[opener] = Some(i);
+                    partners[i] = Some(opener);
+                }
+            }
+            _ => {}
+        }
+        
+        match token.token_type {
+            TokenType::Label => pending_labels.push(token.text),
+            TokenType::Punctuation if token.text == ":" => {}
+            _ => pending_labels.clear(),
+        }
+    }
+    
+    partners
+}
+
+/// Line counts for code metrics; every line is exactly one of blank, comment
+/// or code
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocMetrics {
+    pub total_lines: usize,
+    /// Lines holding only whitespace
+    pub blank_lines: usize,
+    /// Lines holding comment text (an inner line of a block comment included)
+    /// and otherwise only whitespace
+    pub comment_lines: usize,
+    /// Lines with any significant token, even if a comment shares the line
+    pub code_lines: usize,
+}
+
+/// `loc` serialized as `{ totalLines, blankLines, commentLines, codeLines }`
+#[wasm_bindgen(js_name = loc)]
+pub fn loc_js(code: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&loc(code)).unwrap_or(JsValue::NULL)
+}
+
+/// Blank, comment-only and code line counts. A line is classified by the
+/// tokens overlapping it, so a string or comment spanning several lines
+/// counts on each of them. A final line without a line break counts; an
+/// empty input has no lines.
+pub fn loc(code: &str) -> LocMetrics {
+    let mut lexer = PLIToken::lexer(code);
+    let tokens: Vec<_> = std::iter::from_fn(|| next_raw(&mut lexer))
+        .filter(|(token_type, _)| !matches!(token_type, TokenType::Whitespace | TokenType::Newline))
+        .collect();
+    
+    let mut metrics = LocMetrics::default();
+    let mut first = 0;
+    let mut line_start = 0;
+    for line in split_lines_inclusive(code) {
+        let line_end = line_start + line.len();
+        while tokens.get(first).is_some_and(|(_, span)| span.end <= line_start) {
+            first += 1;
+        }
+        let on_line = tokens[first..].iter().take_while(|(_, span)| span.start < line_end);
+        let mut has_comment = false;
+        let mut has_code = false;
+        for (token_type, _) in on_line {
+            has_comment |= *token_type == TokenType::Comment;
+            has_code |= *token_type != TokenType::Comment;
+        }
+        
+        metrics.total_lines += 1;
+        if has_code {
+            metrics.code_lines += 1;
+        } else if has_comment {
+            metrics.comment_lines += 1;
+        } else {
+            metrics.blank_lines += 1;
+        }
+        line_start = line_end;
+    }
+    
+    metrics
+}
+
+/// Aggregate token counts for a source file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenStats {
+    /// Number of tokens of every kind, trivia included
+    pub total_tokens: usize,
+    /// Bytes covered by all tokens (the whole input)
+    pub total_bytes: usize,
+    /// Count per token type, keyed by its lowercase name; `whitespace` and
+    /// `newline` are trivia and are listed like any other type
+    pub histogram: BTreeMap<TokenType, usize>,
+}
+
+impl TokenStats {
+    /// Count for a single type, 0 if it never occurs
+    pub fn count(&self, token_type: TokenType) -> usize {
+        self.histogram.get(&token_type).copied().unwrap_or(0)
+    }
+    
+    fn record(&mut self, token: &TokenRef<'_>) {
+        self.total_tokens += 1;
+        self.total_bytes += token.end - token.start;
+        *self.histogram.entry(token.token_type).or_insert(0) += 1;
+    }
+}
+
+/// `token_stats` serialized as `{ totalTokens, totalBytes, histogram }`
+#[wasm_bindgen(js_name = token_stats)]
+pub fn token_stats_js(code: &str) -> JsValue {
+    let serializer = serde_wasm_bindgen::Serializer::new().serialize_maps_as_objects(true);
+    token_stats(code).serialize(&serializer).unwrap_or(JsValue::NULL)
+}
+
+/// Single pass over the lexer counting tokens - no token vector is built.
+/// Context-dependent types (labels, pictures) are counted as their lexical
+/// type, e.g. a label as `Identifier`.
+pub fn token_stats(code: &str) -> TokenStats {
+    let mut stats = TokenStats::default();
+    let mut stream = TokenStream::new(code);
+    
+    while let Some(token) = stream.next_ref() {
+        stats.record(&token);
+    }
+    
+    stats
+}
+
+/// Token-type legend for `semantic_tokens_lsp`; register it as
+/// `SemanticTokensLegend.tokenTypes` (no modifiers are used)
+pub const SEMANTIC_TOKEN_LEGEND: [&str; 11] = [
+    "keyword", "type", "string", "comment", "number",
+    "operator", "macro", "function", "variable", "label",
+    "event",
+];
+
+/// The legend as a JS string array
+#[wasm_bindgen]
+pub fn semantic_tokens_legend() -> Vec<String> {
+    SEMANTIC_TOKEN_LEGEND.iter().map(|s| s.to_string()).collect()
+}
+
+/// Index into `SEMANTIC_TOKEN_LEGEND`, or None for tokens LSP doesn't color
+fn lsp_token_type(token_type: TokenType) -> Option<u32> {
+    match token_type {
+        TokenType::Keyword | TokenType::KeywordControl | TokenType::KeywordStorage | TokenType::KeywordIo | TokenType::FormatItem => Some(0),
+        TokenType::KeywordType => Some(1),
+        TokenType::String | TokenType::StringDouble | TokenType::GraphicString | TokenType::Picture => Some(2),
+        TokenType::Comment | TokenType::SequenceNumber => Some(3),
+        TokenType::Number => Some(4),
+        TokenType::Operator => Some(5),
+        TokenType::Preprocessor | TokenType::Directive => Some(6),
+        TokenType::Builtin | TokenType::Pseudovariable => Some(7),
+        TokenType::Identifier => Some(8),
+        TokenType::Label => Some(9),
+        TokenType::Condition => Some(10),
+        TokenType::Punctuation | TokenType::Whitespace | TokenType::Newline | TokenType::Unknown | TokenType::Eof => None,
+    }
+}
+
+/// LSP `SemanticTokens.data`: 5-tuples of [deltaLine, deltaStartChar, length,
+/// tokenType, tokenModifiers], each relative to the previous emitted token.
+/// Characters are UTF-16 code units as LSP mandates. Whitespace, newlines and
+/// punctuation are skipped; multi-line tokens are split into one entry per line.
+#[wasm_bindgen]
+pub fn semantic_tokens_lsp(code: &str) -> Vec<u32> {
+    let mut data = Vec::new();
+    let (mut line, mut column) = (0u32, 0u32);
+    let (mut prev_line, mut prev_column) = (0u32, 0u32);
+    
+    for token in tokenize_borrowed(code) {
+        let legend_index = lsp_token_type(token.token_type);
+        for piece in split_lines_inclusive(token.text) {
+            let content = piece.trim_end_matches(['\n', '\r']);
+            let length = content.encode_utf16().count() as u32;
+            if let Some(index) = legend_index.filter(|_| length > 0) {
+                let delta_line = line - prev_line;
+                let delta_start = if delta_line == 0 { column - prev_column } else { column };
+                data.extend_from_slice(&[delta_line, delta_start, length, index, 0]);
+                prev_line = line;
+                prev_column = column;
+            }
+            column += length;
+            if content.len() < piece.len() {
+                line += 1;
+                column = 0;
+            }
+        }
+    }
+    
+    data
+}
+
+/// What a folding range collapses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FoldKind {
+    Block,
+    Comment,
+    Preprocessor,
+}
+
+/// A collapsible region, 0-based inclusive lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FoldRange {
+    pub start_line: usize,
+    pub end_line: usize,
+    pub kind: FoldKind,
+}
+
+/// Folding ranges serialized as `{ startLine, endLine, kind }[]`
+#[wasm_bindgen(js_name = folding_ranges)]
+pub fn folding_ranges_js(code: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&folding_ranges(code)).unwrap_or(JsValue::NULL)
+}
+
+/// Folding ranges for PROC/DO/BEGIN/SELECT blocks, multi-line comments and
+/// %DO/%END groups, ordered by start line with enclosing ranges first.
+/// Regions that start and end on the same line are omitted.
+pub fn folding_ranges(code: &str) -> Vec<FoldRange> {
+    let tokens = tokenize_borrowed(code);
+    let partners = pair_table(&tokens);
+    let mut ranges = Vec::new();
+    let mut percent_do: Vec<usize> = Vec::new();
+    
+    for (i, token) in tokens.iter().enumerate() {
+        match token.token_type {
+            TokenType::Comment => {
+                let end_line = token.line + line_break_count(token.text);
+                ranges.push(FoldRange { start_line: token.line, end_line, kind: FoldKind::Comment });
+            }
+            TokenType::Preprocessor if token.text.eq_ignore_ascii_case("%DO") => percent_do.push(token.line),
+            TokenType::Preprocessor if token.text.eq_ignore_ascii_case("%END") => {
+                if let Some(start_line) = percent_do.pop() {
+                    ranges.push(FoldRange { start_line, end_line: token.line, kind: FoldKind::Preprocessor });
+                }
+            }
+            _ if is_block_opener(token) => {
+                if let Some(end) = partners[i] {
+                    ranges.push(FoldRange { start_line: token.line, end_line: tokens[end].line, kind: FoldKind::Block });
+                }
+            }
+            _ => {}
+        }
+    }
+    
+    ranges.retain(|r| r.end_line > r.start_line);
+    ranges.sort_by_key(|r| (r.start_line, std::cmp::Reverse(r.end_line)));
+    ranges
+}
+
+/// Block nesting depth at the start of each line (one entry per line,
+/// including an empty last line after a final newline), counting
+/// PROC/DO/BEGIN/SELECT blocks opened on earlier lines and not yet closed.
+/// A line that begins with a block's `END` already reports the outer depth, so
+/// editors dedent it; a block left open runs to the end of the source.
+/// Parenthesized continuations are not counted.
+#[wasm_bindgen]
+pub fn indent_levels(code: &str) -> Vec<i32> {
+    let tokens = tokenize_borrowed(code);
+    let partners = pair_table(&tokens);
+    
+    // Where each line's content begins: its first non-blank token, or the
+    // line start for lines inside a comment or string
+    let mut anchors: Vec<usize> = split_lines_inclusive(code)
+        .scan(0, |start, line| {
+            let anchor = *start;
+            *start += line.len();
+            Some(anchor)
+        })
+        .collect();
+    anchors.resize(line_break_count(code) + 1, code.len());
+    let mut anchored = vec![false; anchors.len()];
+    for token in tokens.iter().filter(|t| !matches!(t.token_type, TokenType::Whitespace | TokenType::Newline)) {
+        if !std::mem::replace(&mut anchored[token.line], true) {
+            anchors[token.line] = token.start;
+        }
+    }
+    
+    // Each block covers the lines after its opener whose content begins
+    // before its END
+    let mut deltas = vec![0i32; anchors.len() + 1];
+    for (i, token) in tokens.iter().enumerate().filter(|(_, t)| is_block_opener(t)) {
+        let first = token.line + 1;
+        let last = match partners[i] {
+            Some(end) => anchors.partition_point(|&anchor| anchor < tokens[end].start),
+            None => anchors.len(),
+        };
+        if first < last {
+            deltas[first] += 1;
+            deltas[last] -= 1;
+        }
+    }
+    
+    deltas[..anchors.len()]
+        .iter()
+        .scan(0, |depth, delta| {
+            *depth += delta;
+            Some(*depth)
+        })
+        .collect()
+}
+
+/// Byte span of one statement, from its first significant token through the
+/// terminating `;` (0-based lines of both ends)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatementRange {
+    pub start: usize,
+    pub end: usize,
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+/// `statement_ranges` serialized as `{ start, end, startLine, endLine }[]`
+#[wasm_bindgen(js_name = statement_ranges)]
+pub fn statement_ranges_js(code: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&statement_ranges(code)).unwrap_or(JsValue::NULL)
+}
+
+/// Statements split at `;` tokens, so semicolons inside strings and comments
+/// never end one. Leading whitespace and comments are not part of a statement;
+/// trailing text without a `;` forms a final, unterminated statement.
+pub fn statement_ranges(code: &str) -> Vec<StatementRange> {
+    statement_ranges_with(code, true)
+}
+
+/// `statement_ranges_with` serialized like `statement_ranges`
+#[wasm_bindgen(js_name = statement_ranges_with)]
+pub fn statement_ranges_with_js(code: &str, comments_are_trivia: bool) -> JsValue {
+    serde_wasm_bindgen::to_value(&statement_ranges_with(code, comments_are_trivia)).unwrap_or(JsValue::NULL)
+}
+
+/// `statement_ranges`, where unless `comments_are_trivia` a comment counts as
+/// statement text: one before a statement starts it, one after the last `;`
+/// forms a trailing statement of its own
+pub fn statement_ranges_with(code: &str, comments_are_trivia: bool) -> Vec<StatementRange> {
+    let mut ranges = Vec::new();
+    let mut current: Option<StatementRange> = None;
+    
+    for token in tokenize_borrowed(code) {
+        if token.token_type.is_trivia_with(comments_are_trivia) {
+            continue;
+        }
+        let end_line = token.line + line_break_count(token.text);
+        let range = current.get_or_insert(StatementRange {
+            start: token.start,
+            end: token.end,
+            start_line: token.line,
+            end_line,
+        });
+        range.end = token.end;
+        range.end_line = end_line;
+        
+        if token.token_type == TokenType::Punctuation && token.text == ";" {
+            ranges.extend(current.take());
+        }
+    }
+    
+    ranges.extend(current);
+    ranges
+}
+
+/// What an outline symbol names
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymbolKind {
+    Procedure,
+    Entry,
+    Label,
+}
+
+/// A named definition for an outline view. For procedures `start..end` runs
+/// from the label to the end of the matching `END` statement; for entries and
+/// statement labels it is the label itself. `parent` is the index of the
+/// enclosing procedure within the same outline.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SymbolInfo {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub start: usize,
+    pub end: usize,
+    pub parent: Option<usize>,
+}
+
+/// `outline` serialized as `{ name, kind, start, end, parent }[]`
+#[wasm_bindgen(js_name = outline)]
+pub fn outline_js(code: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&outline(code)).unwrap_or(JsValue::NULL)
+}
+
+/// Procedures, entry points and statement labels in source order
+pub fn outline(code: &str) -> Vec<SymbolInfo> {
+    let tokens = tokenize_borrowed(code);
+    let partners = pair_table(&tokens);
+    let significant: Vec<usize> = (0..tokens.len())
+        .filter(|&i| !tokens[i].token_type.is_trivia())
+        .collect();
+    let mut symbols: Vec<SymbolInfo> = Vec::new();
+    // Open procedures: (symbol index, END token index)
+    let mut open: Vec<(usize, usize)> = Vec::new();
+    let mut k = 0;
+    
+    while k < significant.len() {
+        let i = significant[k];
+        while open.last().is_some_and(|&(_, end)| end < i) {
+            open.pop();
+        }
+        if tokens[i].token_type != TokenType::Label {
+            k += 1;
+            continue;
+        }
+        
+        // A chain of labels `A: B:` in front of one statement
+        let mut labels = Vec::new();
+        while let Some(&j) = significant.get(k).filter(|&&j| tokens[j].token_type == TokenType::Label) {
+            labels.push(j);
+            k += 1;
+            if significant.get(k).is_some_and(|&c| tokens[c].text == ":") {
+                k += 1;
+            }
+        }
+        
+        let statement = significant.get(k).map(|&j| &tokens[j]);
+        let keyword_is = |word: &str| statement.is_some_and(|t| t.token_type.is_keyword() && t.text.eq_ignore_ascii_case(word));
+        let kind = if keyword_is("PROC") || keyword_is("PROCEDURE") {
+            SymbolKind::Procedure
+        } else if keyword_is("ENTRY") {
+            SymbolKind::Entry
+        } else {
+            SymbolKind::Label
+        };
+        let end_token = significant.get(k).and_then(|&j| partners[j]).filter(|_| kind == SymbolKind::Procedure);
+        let procedure_end = end_token
+            .map(|end| {
+                let semicolon = tokens[end..].iter().find(|t| t.text == ";").unwrap_or(&tokens[end]);
+                semicolon.end
+            })
+            .unwrap_or(code.len());
+        
+        let parent = open.last().map(|&(symbol, _)| symbol);
+        let first = symbols.len();
+        for &j in &labels {
+            let end = if kind == SymbolKind::Procedure { procedure_end } else { tokens[j].end };
+            symbols.push(SymbolInfo { name: tokens[j].text.to_string(), kind, start: tokens[j].start, end, parent });
+        }
+        if kind == SymbolKind::Procedure {
+            open.push((first, end_token.unwrap_or(tokens.len())));
+        }
+    }
+    
+    symbols
+}
+
+/// Member referenced by `%INCLUDE`/`%XINCLUDE`; `start..end` covers the
+/// whole reference (`MEMBER` or `DDNAME(MEMBER)`)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IncludeRef {
+    pub ddname: Option<String>,
+    pub member: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// `includes` serialized as `{ ddname, member, start, end }[]`
+#[wasm_bindgen(js_name = includes)]
+pub fn includes_js(code: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&includes(code)).unwrap_or(JsValue::NULL)
+}
+
+/// Every member named by `%INCLUDE`/`%XINCLUDE`, including comma-separated lists
+pub fn includes(code: &str) -> Vec<IncludeRef> {
+    let tokens: Vec<TokenRef<'_>> = tokenize_borrowed(code)
+        .into_iter()
+        .filter(|t| !t.token_type.is_trivia())
+        .collect();
+    let mut refs = Vec::new();
+    let mut i = 0;
+    
+    while i < tokens.len() {
+        let is_include = tokens[i].token_type == TokenType::Preprocessor
+            && (tokens[i].text.eq_ignore_ascii_case("%INCLUDE") || tokens[i].text.eq_ignore_ascii_case("%XINCLUDE"));
+        i += 1;
+        if !is_include {
+            continue;
+        }
+        
+        while let Some(name) = tokens.get(i).filter(|t| t.token_type.is_word()) {
+            let qualified = match tokens.get(i + 1..i + 4) {
+                Some([open, member, close]) if open.text == "(" && member.token_type.is_word() && close.text == ")" => {
+                    Some((member, close))
+                }
+                _ => None,
+            };
+            let (ddname, member, end) = match qualified {
+                Some((member, close)) => (Some(name.text.to_string()), member.text, close.end),
+                None => (None, name.text, name.end),
+            };
+            refs.push(IncludeRef { ddname, member: member.to_string(), start: name.start, end });
+            i += if qualified.is_some() { 4 } else { 1 };
+            
+            if tokens.get(i).is_none_or(|t| t.text != ",") {
+                break;
+            }
+            i += 1;
+        }
+    }
+    
+    refs
+}
+
+/// A tool directive embedded in a comment, such as `/*@SUPPRESS SIZE*/`;
+/// `text` is what follows the prefix, trimmed, and `start..end` spans the
+/// whole comment
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommentDirective {
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// `comment_directives` serialized as `{ text, start, end }[]`
+#[wasm_bindgen(js_name = comment_directives)]
+pub fn comment_directives_js(code: &str, prefix: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&comment_directives(code, prefix)).unwrap_or(JsValue::NULL)
+}
+
+/// Comments whose content, once blanks are trimmed, starts with `prefix`
+/// (case-sensitive). With prefix `@`, `/* @SUPPRESS SIZE */` yields
+/// `SUPPRESS SIZE`; an unterminated comment is still scanned.
+pub fn comment_directives(code: &str, prefix: &str) -> Vec<CommentDirective> {
+    tokenize_borrowed(code)
+        .into_iter()
+        .filter(|t| t.token_type == TokenType::Comment)
+        .filter_map(|t| {
+            let body = t.text.strip_prefix("/*").unwrap_or(t.text);
+            let body = body.strip_suffix("*/").unwrap_or(body);
+            let directive = body.trim().strip_prefix(prefix)?;
+            Some(CommentDirective { text: directive.trim().to_string(), start: t.start, end: t.end })
+        })
+        .collect()
+}
+
+/// One member of a concatenated library listing. `name` comes from the
+/// boundary line that opened it (`None` for text before the first boundary);
+/// token `start`/`end` index the whole input, while `line` counts from the
+/// member's first line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemberTokens {
+    pub name: Option<String>,
+    pub tokens: Vec<Token>,
+}
+
+/// `tokenize_members` serialized as `{ name, tokens }[]`; an invalid
+/// `boundary_regex` throws
+#[wasm_bindgen(js_name = tokenize_members)]
+pub fn tokenize_members_js(code: &str, boundary_regex: &str) -> Result<JsValue, JsError> {
+    let members = tokenize_members(code, boundary_regex).map_err(|e| JsError::new(&e.to_string()))?;
+    Ok(serde_wasm_bindgen::to_value(&members).unwrap_or(JsValue::NULL))
+}
+
+/// Split members concatenated by a build tool (`./ ADD NAME=MEMBER` IEBUPDTE
+/// control statements, `++` separators) and tokenize each on its own, so an
+/// unterminated comment or string ends with its member. A boundary is any
+/// line `boundary_regex` matches at its start (without the line break); the
+/// line itself belongs to no member. The member name is the `name` capture
+/// group, or else group 1: `^\./ ADD NAME=(\w+)`. Text before the first
+/// boundary forms an unnamed member unless it is empty.
+pub fn tokenize_members(code: &str, boundary_regex: &str) -> Result<Vec<MemberTokens>, regex::Error> {
+    let boundary = regex::Regex::new(&format!("^(?:{})", boundary_regex))?;
+    
+    // (name, member text start) for every member, plus where each one ends
+    let mut members: Vec<(Option<String>, usize)> = vec![(None, 0)];
+    let mut ends = Vec::new();
+    let mut at = 0;
+    for line in split_lines_inclusive(code) {
+        if let Some(captures) = boundary.captures(line.trim_end_matches(['\n', '\r'])) {
+            let name = captures.name("name").or_else(|| captures.get(1)).map(|m| m.as_str().to_string());
+            ends.push(at);
+            members.push((name, at + line.len()));
+        }
+        at += line.len();
+    }
+    ends.push(code.len());
+    let skip_leading = usize::from(ends[0] == 0);
+    
+    Ok(members
+        .into_iter()
+        .zip(ends)
+        .skip(skip_leading)
+        .map(|((name, start), end)| {
+            let mut tokens = tokenize(&code[start..end]);
+            for token in &mut tokens {
+                token.start += start;
+                token.end += start;
+            }
+            MemberTokens { name, tokens }
+        })
+        .collect())
+}
+
+/// Structure-qualified reference such as `PAYROLL.EMPLOYEE.NAME`; `start..end`
+/// spans the name from its first to its last component
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QualifiedRef {
+    pub components: Vec<String>,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// `qualified_references` serialized as `{ components, start, end }[]`
+#[wasm_bindgen(js_name = qualified_references)]
+pub fn qualified_references_js(code: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&qualified_references(code)).unwrap_or(JsValue::NULL)
+}
+
+/// Every `name . name {. name}` run. PL/I allows blanks and comments around
+/// the periods, so trivia between components is skipped. A decimal point is
+/// part of its `Number` token (`3.14`, `X.5` lexes as `X` then `.5`), so
+/// numbers never take part; subscripted forms like `A(1).B` are not joined.
+pub fn qualified_references(code: &str) -> Vec<QualifiedRef> {
+    let tokens: Vec<TokenRef<'_>> = tokenize_borrowed(code)
+        .into_iter()
+        .filter(|t| !t.token_type.is_trivia())
+        .collect();
+    let mut refs = Vec::new();
+    let mut i = 0;
+    
+    while i < tokens.len() {
+        if !tokens[i].token_type.is_word() {
+            i += 1;
+            continue;
+        }
+        let first = i;
+        while let Some([dot, name]) = tokens.get(i + 1..i + 3) {
+            if dot.text != "." || !name.token_type.is_word() {
+                break;
+            }
+            i += 2;
+        }
+        if i > first {
+            refs.push(QualifiedRef {
+                components: tokens[first..=i].iter().step_by(2).map(|t| t.text.to_string()).collect(),
+                start: tokens[first].start,
+                end: tokens[i].end,
+            });
+        }
+        i += 1;
+    }
+    
+    refs
+}
+
+/// Constant operands joined by `||`, such as `'AB' || 'CD'`; `start..end`
+/// spans the whole expression and `operands` holds each operand's span
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConcatGroup {
+    pub start: usize,
+    pub end: usize,
+    pub operands: Vec<(usize, usize)>,
+}
+
+/// `concat_groups` serialized as `{ start, end, operands: [start, end][] }[]`
+#[wasm_bindgen(js_name = concat_groups)]
+pub fn concat_groups_js(code: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&concat_groups(code)).unwrap_or(JsValue::NULL)
+}
+
+/// Every run of string or number constants joined by `||`, with blanks and
+/// comments allowed in between. A run stops at the first operand that is not
+/// a constant, so `'A' || X || 'B'` has no group; a lone constant is none either.
+pub fn concat_groups(code: &str) -> Vec<ConcatGroup> {
+    let is_constant = |t: &TokenRef<'_>| {
+        t.token_type.is_string() || t.token_type == TokenType::Number
+    };
+    let tokens: Vec<TokenRef<'_>> = tokenize_borrowed(code)
+        .into_iter()
+        .filter(|t| !t.token_type.is_trivia())
+        .collect();
+    let mut groups = Vec::new();
+    let mut i = 0;
+    
+    while i < tokens.len() {
+        if !is_constant(&tokens[i]) {
+            i += 1;
+            continue;
+        }
+        let mut operands = vec![(tokens[i].start, tokens[i].end)];
+        while let Some([op, operand]) = tokens.get(i + 1..i + 3) {
+            if op.text != "||" || !is_constant(operand) {
+                break;
+            }
+            operands.push((operand.start, operand.end));
+            i += 2;
+        }
+        if operands.len() > 1 {
+            groups.push(ConcatGroup { start: operands[0].0, end: tokens[i].end, operands });
+        }
+        i += 1;
+    }
+    
+    groups
+}
+
+/// `SemanticToken::modifiers` bit: a name introduced by DCL/DECLARE
+pub const MODIFIER_DECLARATION: u32 = 1 << 0;
+/// `SemanticToken::modifiers` bit: a builtin (or pseudovariable) followed by
+/// its parenthesized argument list
+pub const MODIFIER_BUILTIN_CALL: u32 = 1 << 1;
+/// `SemanticToken::modifiers` bit: a statement label
+pub const MODIFIER_LABEL: u32 = 1 << 2;
+
+/// A token with semantic modifier bits (`MODIFIER_*`) on top of its type
+#[derive(Debug, Clone, Serialize)]
+pub struct SemanticToken {
+    pub text: String,
+    #[serde(rename = "type")]
+    pub token_type: TokenType,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+    pub modifiers: u32,
+}
+
+/// `tokenize_semantic` serialized as `{ text, type, start, end, line, column, modifiers }[]`
+#[wasm_bindgen(js_name = tokenize_semantic)]
+pub fn tokenize_semantic_js(code: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&tokenize_semantic(code)).unwrap_or(JsValue::NULL)
+}
+
+/// `tokenize` plus per-token modifier bits for semantic highlighting. The
+/// declaration bit marks declared names at their DCL site only, not their uses.
+pub fn tokenize_semantic(code: &str) -> Vec<SemanticToken> {
+    let tokens = tokenize(code);
+    let significant: Vec<usize> = (0..tokens.len())
+        .filter(|&i| !tokens[i].token_type.is_trivia())
+        .collect();
+    let mut modifiers = vec![0u32; tokens.len()];
+    
+    for i in declared_names(&tokens, &significant) {
+        modifiers[i] |= MODIFIER_DECLARATION;
+    }
+    for &i in &significant {
+        let token = &tokens[i];
+        if token.token_type == TokenType::Label {
+            modifiers[i] |= MODIFIER_LABEL;
+        }
+        let is_builtin = matches!(token.token_type, TokenType::Builtin | TokenType::Pseudovariable);
+        if is_builtin && next_significant(&tokens, i, true).is_some_and(|j| tokens[j].text == "(") {
+            modifiers[i] |= MODIFIER_BUILTIN_CALL;
+        }
+    }
+    
+    tokens
+        .into_iter()
+        .zip(modifiers)
+        .map(|(token, modifiers)| SemanticToken {
+            text: token.text,
+            token_type: token.token_type,
+            start: token.start,
+            end: token.end,
+            line: token.line,
+            column: token.column,
+            modifiers,
+        })
+        .collect()
+}
+
+/// Conventional TextMate scope for a token type, for scope-based themes and
+/// grammar snapshots. Trivia and the end marker get the root `source.pli`.
+pub fn textmate_scope(token_type: TokenType) -> &'static str {
+    match token_type {
+        TokenType::Keyword => "keyword.pli",
+        TokenType::KeywordControl => "keyword.control.pli",
+        TokenType::KeywordType => "storage.type.pli",
+        TokenType::KeywordStorage => "storage.modifier.pli",
+        TokenType::KeywordIo => "keyword.other.io.pli",
+        TokenType::String => "string.quoted.single.pli",
+        TokenType::StringDouble => "string.quoted.double.pli",
+        TokenType::GraphicString => "string.quoted.other.graphic.pli",
+        TokenType::Picture => "string.other.picture.pli",
+        TokenType::Comment => "comment.block.pli",
+        TokenType::SequenceNumber => "comment.line.sequence-number.pli",
+        TokenType::Number => "constant.numeric.pli",
+        TokenType::Operator => "keyword.operator.pli",
+        TokenType::Preprocessor => "meta.preprocessor.pli",
+        TokenType::Directive => "keyword.control.directive.pli",
+        TokenType::Builtin => "support.function.builtin.pli",
+        TokenType::Pseudovariable => "support.function.pseudovariable.pli",
+        TokenType::Condition => "support.constant.condition.pli",
+        TokenType::FormatItem => "keyword.other.format.pli",
+        TokenType::Identifier => "variable.other.pli",
+        TokenType::Label => "entity.name.label.pli",
+        TokenType::Punctuation => "punctuation.pli",
+        TokenType::Unknown => "invalid.illegal.pli",
+        TokenType::Whitespace | TokenType::Newline | TokenType::Eof => "source.pli",
+    }
+}
+
+/// Nearest Pygments/Rouge token path for a token type, for Pygments-compatible
+/// formatters
+pub fn pygments_token(token_type: TokenType) -> &'static str {
+    match token_type {
+        TokenType::Keyword | TokenType::KeywordControl | TokenType::KeywordIo => "Token.Keyword",
+        TokenType::KeywordType => "Token.Keyword.Type",
+        TokenType::KeywordStorage => "Token.Keyword.Declaration",
+        TokenType::FormatItem => "Token.Keyword.Pseudo",
+        TokenType::String => "Token.Literal.String.Single",
+        TokenType::StringDouble => "Token.Literal.String.Double",
+        TokenType::GraphicString | TokenType::Picture => "Token.Literal.String.Other",
+        TokenType::Comment => "Token.Comment.Multiline",
+        TokenType::SequenceNumber => "Token.Comment.Special",
+        TokenType::Number => "Token.Literal.Number",
+        TokenType::Operator => "Token.Operator",
+        TokenType::Preprocessor | TokenType::Directive => "Token.Comment.Preproc",
+        TokenType::Builtin => "Token.Name.Builtin",
+        TokenType::Pseudovariable => "Token.Name.Builtin.Pseudo",
+        TokenType::Condition => "Token.Name.Exception",
+        TokenType::Identifier => "Token.Name",
+        TokenType::Label => "Token.Name.Label",
+        TokenType::Punctuation => "Token.Punctuation",
+        TokenType::Unknown => "Token.Error",
+        TokenType::Whitespace | TokenType::Newline => "Token.Text.Whitespace",
+        TokenType::Eof => "Token.Text",
+    }
+}
+
+/// `pygments_tokens` serialized as `[tokenName, text][]`
+#[wasm_bindgen(js_name = pygments_tokens)]
+pub fn pygments_tokens_js(code: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&pygments_tokens(code)).unwrap_or(JsValue::NULL)
+}
+
+/// `(pygments_token_name, text)` for every token, the stream a Pygments
+/// `get_tokens()` would yield; the texts concatenate back to `code`
+pub fn pygments_tokens(code: &str) -> Vec<(String, String)> {
+    tokenize_borrowed(code)
+        .iter()
+        .map(|t| (pygments_token(t.token_type).to_string(), t.text.to_string()))
+        .collect()
+}
+
+/// A token annotated with its `textmate_scope`
+#[derive(Debug, Clone, Serialize)]
+pub struct ScopedToken {
+    pub text: String,
+    #[serde(rename = "type")]
+    pub token_type: TokenType,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+    pub scope: &'static str,
+}
+
+/// `scoped_tokens` serialized as `{ text, type, start, end, line, column, scope }[]`
+#[wasm_bindgen(js_name = scoped_tokens)]
+pub fn scoped_tokens_js(code: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&scoped_tokens(code)).unwrap_or(JsValue::NULL)
+}
+
+/// `tokenize` with each token's TextMate scope attached
+pub fn scoped_tokens(code: &str) -> Vec<ScopedToken> {
+    tokenize_borrowed(code)
+        .into_iter()
+        .map(|token| ScopedToken {
+            text: token.text.to_string(),
+            token_type: token.token_type,
+            start: token.start,
+            end: token.end,
+            line: token.line,
+            column: token.column,
+            scope: textmate_scope(token.token_type),
+        })
+        .collect()
+}
+
+/// Name of a `tokenize_flat` type code (`"keyword"`, `"string"`, ...), or an
+/// empty string for an unknown code
+#[wasm_bindgen]
+pub fn token_type_name(code: u32) -> String {
+    TokenType::from_code(code).map(TokenType::name).unwrap_or_default().to_string()
+}
+
+/// Inverse of `token_type_name` (case-insensitive); -1 for an unknown name
+#[wasm_bindgen]
+pub fn token_type_code(name: &str) -> i32 {
+    TokenType::ALL
+        .iter()
+        .find(|t| t.name().eq_ignore_ascii_case(name))
+        .map_or(-1, |&t| t as i32)
+}
+
+/// Get version info
+#[wasm_bindgen]
+pub fn version() -> String {
+    env!("CARGO_PKG_VERSION").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_basic_tokenization() {
+        let code = "DCL X FIXED BINARY(31);";
+        let tokens = tokenize(code);
+        
+        assert!(!tokens.is_empty());
+        assert_eq!(tokens[0].token_type, TokenType::Keyword); // DCL
+    }
+    
+    #[test]
+    fn test_keyword_subcategories() {
+        let code = "IF X THEN DCL Y FIXED STATIC; PUT SKIP;";
+        let types: Vec<TokenType> = tokenize(code)
+            .into_iter()
+            .filter(|t| t.token_type != TokenType::Whitespace)
+            .map(|t| t.token_type)
+            .collect();
+        
+        assert_eq!(types[0], TokenType::KeywordControl); // IF
+        assert_eq!(types[2], TokenType::KeywordControl); // THEN
+        assert_eq!(types[3], TokenType::Keyword);        // DCL
+        assert_eq!(types[5], TokenType::KeywordType);    // FIXED
+        assert_eq!(types[6], TokenType::KeywordStorage); // STATIC
+        assert_eq!(types[8], TokenType::KeywordIo);      // PUT
+        assert_eq!(types[9], TokenType::KeywordIo);      // SKIP
+        
+        // Appended variants keep the original flat codes stable
+        assert_eq!(TokenType::Unknown as u32, 11);
+        assert_eq!(TokenType::KeywordControl as u32, 12);
+        assert_eq!(TokenType::KeywordIo as u32, 15);
+        assert_eq!(serde_json::to_string(&TokenType::KeywordControl).unwrap(), "\"keywordcontrol\"");
+    }
+    
+    #[test]
+    fn test_comment() {
+        let code = "/* This is a comment */ DCL X;";
+        let tokens = tokenize(code);
+        
+        assert_eq!(tokens[0].token_type, TokenType::Comment);
+    }
+    
+    #[test]
+    fn test_string() {
+        let code = "X = 'Hello World';";
+        let tokens = tokenize(code);
+        
+        let string_token = tokens.iter().find(|t| t.token_type == TokenType::String);
+        assert!(string_token.is_some());
+    }
+    
+    #[test]
+    fn test_string_quote_kinds() {
+        let tokens = significant("'abc' \"abc\" 'a\"b' \"a'b\"");
+        let types: Vec<(&str, TokenType)> = tokens
+            .iter()
+            .map(|t| (t.text.as_str(), t.token_type))
+            .collect();
+        assert_eq!(types, [
+            ("'abc'", TokenType::String),
+            ("\"abc\"", TokenType::StringDouble),
+            ("'a\"b'", TokenType::String),
+            ("\"a'b\"", TokenType::StringDouble),
+        ]);
+        assert_eq!(significant("PIC \"Z9\"")[1].token_type, TokenType::Picture);
+    }
+    
+    #[test]
+    fn test_comment_markers_in_strings() {
+        let tokens = significant("X = '/* not a comment */';");
+        assert_eq!((tokens[2].text.as_str(), tokens[2].token_type), ("'/* not a comment */'", TokenType::String));
+        assert_eq!(tokens[3].text, ";");
+        
+        let tokens = significant("A = '*/'; B = \"/*\";");
+        assert_eq!((tokens[2].text.as_str(), tokens[2].token_type), ("'*/'", TokenType::String));
+        assert_eq!((tokens[6].text.as_str(), tokens[6].token_type), ("\"/*\"", TokenType::StringDouble));
+        assert_eq!(tokens.len(), 8);
+    }
+    
+    #[test]
+    fn test_quotes_in_comments() {
+        let tokens = tokenize("/* it's fine */ B = 1; /* \"x */");
+        assert_eq!((tokens[0].text.as_str(), tokens[0].token_type), ("/* it's fine */", TokenType::Comment));
+        assert_eq!(tokens.last().map(|t| t.token_type), Some(TokenType::Comment));
+        assert!(tokens.iter().all(|t| !t.token_type.is_string() && t.token_type != TokenType::Unknown));
+        assert!(diagnostics("/* it's fine */ B = 1;").is_empty());
+    }
+    
+    #[test]
+    fn test_parse_picture() {
+        use PictureCategory::*;
+        let elements = parse_picture("'$ZZ9.99'");
+        let summary: Vec<(&str, PictureCategory)> = elements.iter().map(|e| (e.text.as_str(), e.category)).collect();
+        assert_eq!(
+            summary,
+            [("$", Currency), ("Z", ZeroSuppress), ("Z", ZeroSuppress), ("9", Digit), (".", Insertion), ("9", Digit), ("9", Digit)]
+        );
+        // Offsets index the spec as passed, quotes included
+        assert_eq!((elements[0].start, elements[6].end), (1, 8));
+        
+        let categories: Vec<PictureCategory> = parse_picture("S999").iter().map(|e| e.category).collect();
+        assert_eq!(categories, [Sign, Digit, Digit, Digit]);
+        
+        // Iteration factors, two-letter credit symbols, scaling and junk
+        let elements = parse_picture("(5)9V99cr F(-2) Q");
+        let summary: Vec<(&str, PictureCategory, usize)> = elements.iter().map(|e| (e.text.as_str(), e.category, e.count)).collect();
+        assert_eq!(
+            summary,
+            [
+                ("(5)9", Digit, 5), ("V", DecimalPoint, 1), ("9", Digit, 1), ("9", Digit, 1), ("cr", Credit, 1),
+                (" ", Invalid, 1), ("F(-2)", Scale, 1), (" ", Invalid, 1), ("Q", Invalid, 1),
+            ]
+        );
+    }
+    
+    #[test]
+    fn test_graphic_strings() {
+        assert_eq!(significant_types("X = G'おは';"), [
+            ("X".into(), TokenType::Identifier),
+            ("=".into(), TokenType::Operator),
+            ("G'おは'".into(), TokenType::GraphicString),
+            (";".into(), TokenType::Punctuation),
+        ]);
+        assert_eq!(significant_types("'ab'G 'ab'm M'x''y' '42C1'GX 'ab'")
+            .into_iter()
+            .map(|(_, t)| t)
+            .collect::<Vec<_>>(), [
+            TokenType::GraphicString,
+            TokenType::GraphicString,
+            TokenType::GraphicString,
+            TokenType::GraphicString,
+            TokenType::String,
+        ]);
+        // A name merely ending in G is still a name
+        assert_eq!(significant_types("FLAG = 'ab';")[0].1, TokenType::Identifier);
+        
+        let value = |code: &str| string_value(&tokenize(code)[0]);
+        assert_eq!(value("G'おは'").as_deref(), Some("おは"));
+        assert_eq!(value("'x''y'M").as_deref(), Some("x'y"));
+    }
+    
+    #[test]
+    fn test_string_value() {
+        let value = |code: &str| string_value(&tokenize(code)[0]);
+        assert_eq!(value("'IT''S'").as_deref(), Some("IT'S"));
+        assert_eq!(value("''").as_deref(), Some(""));
+        assert_eq!(value("''''").as_deref(), Some("'"));
+        assert_eq!(value("\"SAY \"\"HI\"\"'\"").as_deref(), Some("SAY \"HI\"'"));
+        assert_eq!(value("X"), None);
+        assert_eq!(value("'FF'X"), None);
+        assert_eq!(value("'open"), None);
+    }
+    
+    #[test]
+    fn test_string_doubled_quotes() {
+        for literal in ["'IT''S'", "''", "'X'''", "'DON''T PANIC'", "\"SAY \"\"HI\"\"\""] {
+            let code = format!("X = {};", literal);
+            let tokens = tokenize(&code);
+            let strings: Vec<&Token> = tokens.iter().filter(|t| t.token_type.is_string()).collect();
+            
+            assert_eq!(strings.len(), 1, "{}", literal);
+            assert_eq!(strings[0].text, literal);
+        }
+    }
+    
+    #[test]
+    fn test_line_and_column() {
+        let code = "/* a\n b */ X = 'é';\nDCL Y;";
+        let tokens = tokenize(code);
+        
+        let x = tokens.iter().find(|t| t.text == "X").unwrap();
+        assert_eq!((x.line, x.column), (1, 6));
+        
+        // Columns count chars, so the multi-byte 'é' is a single column
+        let semi = tokens.iter().find(|t| t.text == ";").unwrap();
+        assert_eq!((semi.line, semi.column), (1, 13));
+        
+        let dcl = tokens.iter().find(|t| t.text == "DCL").unwrap();
+        assert_eq!((dcl.line, dcl.column), (2, 0));
+        
+        let flat = tokenize_flat_lc(code);
+        assert_eq!(flat.len(), tokens.len() * 5);
+        for (chunk, token) in flat.chunks(5).zip(&tokens) {
+            assert_eq!(chunk[3] as usize, token.line);
+            assert_eq!(chunk[4] as usize, token.column);
+        }
+    }
+    
+    fn significant(code: &str) -> Vec<Token> {
+        tokenize(code).into_iter().filter(|t| !t.token_type.is_trivia()).collect()
+    }
+    
+    /// `(text, type)` of every significant token
+    fn significant_types(code: &str) -> Vec<(String, TokenType)> {
+        significant(code).into_iter().map(|t| (t.text, t.token_type)).collect()
+    }
+    
+    /// Type `tokenize_flat` reports for the first token spelled `text`
+    fn flat_type_of(code: &str, text: &str) -> Option<TokenType> {
+        tokenize_flat(code)
+            .chunks(3)
+            .find(|r| &code[r[1] as usize..r[2] as usize] == text)
+            .and_then(|r| TokenType::from_code(r[0]))
+    }
+    
+    #[test]
+    fn test_reclassify_declared_names() {
+        let mut tokens = tokenize("DCL DATE FIXED BIN; X = DATE;");
+        reclassify_declared_names(&mut tokens);
+        let dates: Vec<(TokenType, Option<&str>)> = tokens
+            .iter()
+            .filter(|t| t.text == "DATE")
+            .map(|t| (t.token_type, t.canonical.as_deref()))
+            .collect();
+        assert_eq!(dates, [(TokenType::Identifier, None), (TokenType::Identifier, None)]);
+        
+        // Structure members and factored lists; statement keywords are left alone
+        let mut tokens = tokenize("DCL 1 S, 2 TIME CHAR(8), (IF, Y) FIXED;\nIF = 1; IF TIME > Y THEN RETURN;");
+        reclassify_declared_names(&mut tokens);
+        let types: Vec<(&str, TokenType)> = tokens
+            .iter()
+            .filter(|t| ["TIME", "IF", "FIXED", "RETURN"].contains(&t.text.as_str()))
+            .map(|t| (t.text.as_str(), t.token_type))
+            .collect();
+        assert_eq!(types, [
+            ("TIME", TokenType::Identifier),
+            ("IF", TokenType::Identifier),
+            ("FIXED", TokenType::KeywordType),
+            ("IF", TokenType::Identifier),
+            ("IF", TokenType::KeywordControl),
+            ("TIME", TokenType::Identifier),
+            ("RETURN", TokenType::KeywordControl),
+        ]);
+    }
+    
+    #[test]
+    fn test_assignment_hints() {
+        let marked = |code: &str| -> Vec<usize> {
+            let tokens = tokenize(code);
+            let hints = assignment_hints(&tokens);
+            assert_eq!(hints.len(), tokens.len());
+            tokens.iter().zip(hints).filter(|(_, h)| *h).map(|(t, _)| t.start).collect()
+        };
+        assert_eq!(marked("X = 1;"), [2]);
+        assert!(marked("IF A = B THEN").is_empty());
+        
+        let code = "IF A = B THEN X(I) = 1; ELSE Y = A = B;";
+        let expected = vec![code.find("X(I) =").unwrap() + 5, code.find("Y =").unwrap() + 2];
+        assert_eq!(marked(code), expected);
+        
+        let code = "L: DO I = 1 TO N WHILE(F = 0); SELECT; WHEN (K = 1) Z = 2; END;";
+        let expected = vec![code.find("I =").unwrap() + 2, code.find("Z =").unwrap() + 2];
+        assert_eq!(marked(code), expected);
+        assert_eq!(assignment_offsets(code), expected.iter().map(|&o| o as u32).collect::<Vec<u32>>());
+    }
+    
+    #[test]
+    fn test_tasking_and_record_io_keywords() {
+        let tokens = significant("READ FILE(F) INTO(REC) KEY(K);");
+        let words: Vec<(&str, TokenType)> = tokens
+            .iter()
+            .filter(|t| t.token_type.is_keyword())
+            .map(|t| (t.text.as_str(), t.token_type))
+            .collect();
+        assert_eq!(
+            words,
+            [("READ", TokenType::KeywordIo), ("FILE", TokenType::KeywordType), ("INTO", TokenType::Keyword), ("KEY", TokenType::Keyword)]
+        );
+        assert_eq!(significant("WAIT(E);")[0].token_type, TokenType::Keyword);
+        
+        // None of the new words shadows a builtin
+        for word in TASKING_KEYWORDS.iter().chain(RECORD_IO_KEYWORDS) {
+            assert_eq!(classify_word(word), TokenType::Keyword, "{}", word);
+            assert_eq!(builtin_category(word), None, "{}", word);
+        }
+    }
+    
+    #[test]
+    fn test_definition_keywords() {
+        assert_eq!(significant_types("DEFINE ALIAS INT FIXED BIN(31);")[..4], [
+            ("DEFINE".into(), TokenType::Keyword),
+            ("ALIAS".into(), TokenType::Keyword),
+            ("INT".into(), TokenType::Identifier),
+            ("FIXED".into(), TokenType::KeywordType),
+        ]);
+        assert_eq!(significant_types("DCL H HANDLE(T);")[..3], [
+            ("DCL".into(), TokenType::Keyword),
+            ("H".into(), TokenType::Identifier),
+            ("HANDLE".into(), TokenType::KeywordType),
+        ]);
+        
+        let words: Vec<TokenType> = significant_types("define ordinal Color (Red VALUE(1)) union; Dft Range(*) type; N = OrdinalName(C);")
+            .into_iter()
+            .filter(|(text, _)| text.len() > 3 || text.eq_ignore_ascii_case("dft"))
+            .map(|(_, t)| t)
+            .collect();
+        assert_eq!(words, [
+            TokenType::Keyword, TokenType::Keyword, TokenType::Identifier, TokenType::Keyword, TokenType::Keyword,
+            TokenType::Keyword, TokenType::Keyword, TokenType::Keyword, TokenType::Builtin,
+        ]);
+        assert_eq!(builtin_category("ordinalvalue"), Some(BuiltinCategory::Misc));
+        for word in DEFINITION_KEYWORDS {
+            assert_eq!(classify_word(word), TokenType::Keyword, "{}", word);
+        }
+    }
+    
+    #[test]
+    fn test_asterisk_bounds() {
+        let stars = |code: &str| -> Vec<TokenType> {
+            significant(code).into_iter().filter(|t| t.text == "*").map(|t| t.token_type).collect()
+        };
+        assert_eq!(stars("DCL M(*,*) FIXED;"), [TokenType::Punctuation, TokenType::Punctuation]);
+        assert_eq!(stars("X = A*B;"), [TokenType::Operator]);
+        assert_eq!(stars("CALL P(A( * , 1 ), B);"), [TokenType::Punctuation]);
+        assert_eq!(stars("DCL S CHAR(*) VARYING; Y = (A * B) * 2;"), [
+            TokenType::Punctuation, TokenType::Operator, TokenType::Operator,
+        ]);
+        assert_eq!(stars("Y = F(*B, C*);"), [TokenType::Operator, TokenType::Operator]);
+        // A comment between the star and its delimiters doesn't matter
+        assert_eq!(stars("DCL V(/* any */ *) BIN;"), [TokenType::Punctuation]);
+    }
+    
+    #[test]
+    fn test_range_colons() {
+        let colons = |code: &str| -> Vec<TokenType> {
+            significant(code).into_iter().filter(|t| t.text == ":").map(|t| t.token_type).collect()
+        };
+        assert_eq!(colons("A(1:10) = 0;"), [TokenType::Operator]);
+        assert_eq!(colons("LOOP: DO;"), [TokenType::Punctuation]);
+        assert_eq!(colons("DCL M(0 : N, -5:5) FIXED;"), [TokenType::Operator, TokenType::Operator]);
+        assert_eq!(colons("L1: L2: X = B(F(1):3);"), [
+            TokenType::Punctuation, TokenType::Punctuation, TokenType::Operator,
+        ]);
+        // A colon in a string or comment is not a token at all
+        assert!(colons("PUT LIST('A:B' /* x:y */);").is_empty());
+        assert_eq!(significant("LOOP: DO;")[0].token_type, TokenType::Label);
+    }
+    
+    #[test]
+    fn test_loop_keywords() {
+        let header = significant_types("DO I = 1 TO N BY 2;");
+        assert_eq!(header[4], ("TO".to_string(), TokenType::Keyword));
+        assert_eq!(header[6], ("BY".to_string(), TokenType::Keyword));
+        
+        let header = significant_types("DO J = 1 REPEAT J+1 UNTIL(J>100);");
+        assert_eq!(header[4], ("REPEAT".to_string(), TokenType::Keyword));
+        assert_eq!(header[8], ("UNTIL".to_string(), TokenType::KeywordControl));
+        assert_eq!(classify_word("upthru"), TokenType::Keyword);
+        assert_eq!(classify_word("DOWNTHRU"), TokenType::Keyword);
+        
+        // The builtin keeps its type, also when called inside a loop header
+        assert_eq!(significant_types("S = REPEAT('AB', 3);")[2], ("REPEAT".to_string(), TokenType::Builtin));
+        assert_eq!(significant_types("DO I = 1 TO LENGTH(REPEAT(S, 2));")[7], ("REPEAT".to_string(), TokenType::Builtin));
+    }
+    
+    #[test]
+    fn test_labels() {
+        let tokens = significant("MAIN: PROC OPTIONS(MAIN);");
+        assert_eq!(tokens[0].token_type, TokenType::Label);
+        
+        let tokens = significant("X = 1;\nLOOP : DO I = 1 TO 10;");
+        let lp = tokens.iter().find(|t| t.text == "LOOP").unwrap();
+        assert_eq!(lp.token_type, TokenType::Label);
+        
+        // Multiple labels on one statement
+        let tokens = significant("A: B: PROC;");
+        assert_eq!(tokens[0].token_type, TokenType::Label);
+        assert_eq!(tokens[2].token_type, TokenType::Label);
+        
+        // A colon away from statement start is not a label marker
+        let tokens = significant("Z = X : Y; DCL A(LO:HI);");
+        assert!(tokens.iter().all(|t| t.token_type != TokenType::Label));
+        let x = tokens.iter().find(|t| t.text == "X").unwrap();
+        assert_eq!(x.token_type, TokenType::Identifier);
+        
+        // Nor is a bare `X : Y`, since `Y;` on its own is no statement
+        for code in ["X : Y;", "A = 1; X : Y;", "X : Y"] {
+            let x = significant(code).into_iter().find(|t| t.text == "X").unwrap();
+            assert_eq!(x.token_type, TokenType::Identifier, "{}", code);
+        }
+        // ...while anything that can start a statement keeps the label
+        for code in ["X : Y = 1;", "X : END;", "X : ;", "X : Y: Z = 1;", "MAIN:"] {
+            assert_eq!(significant(code)[0].token_type, TokenType::Label, "{}", code);
+        }
+        
+        // A WHEN clause's selector list ends where its statement starts
+        let t = significant_types("SELECT; WHEN (F(K)) L: SUBSTR(S, 1, 1) = 'A'; END;");
+        assert_eq!(t[9], ("L".to_string(), TokenType::Label));
+        assert_eq!(t[11], ("SUBSTR".to_string(), TokenType::Pseudovariable));
+    }
+    
+    #[test]
+    fn test_tokenize_flat_labels() {
+        // The flat path the editor uses applies the same post-passes
+        let code = "MAIN: PROC;\n  L1: L2: X = 1;\nEND MAIN;";
+        let flat = tokenize_flat(code);
+        let expected: Vec<u32> = tokenize(code)
+            .iter()
+            .flat_map(|t| [t.token_type as u32, t.start as u32, t.end as u32])
+            .collect();
+        assert_eq!(flat, expected);
+        assert_eq!(flat.chunks(3).filter(|r| r[0] == TokenType::Label as u32).count(), 3);
+    }
+    
+    #[test]
+    fn test_fixed_sequence_numbers() {
+        let card = format!("{:<72}{}", " DCL X FIXED;", "00010000");
+        assert_eq!(card.len(), 80);
+        let code = format!("{}\n X = 1;\n", card);
+        let tokens = tokenize_fixed_tokens(&code, 73);
+        
+        let seq: Vec<&Token> = tokens.iter().filter(|t| t.token_type == TokenType::SequenceNumber).collect();
+        assert_eq!(seq.len(), 1);
+        assert_eq!(seq[0].text, "00010000");
+        assert_eq!(seq[0].column, 72);
+        assert!(tokens.iter().all(|t| t.token_type != TokenType::Number || t.text == "1"));
+        
+        // The short second line has no sequence field and lexes normally
+        let x = tokens.iter().find(|t| t.text == "X" && t.line == 1).unwrap();
+        assert_eq!(x.token_type, TokenType::Identifier);
+        
+        let flat = tokenize_fixed(&code, 73);
+        assert_eq!(flat.len(), tokens.len() * 3);
+    }
+    
+    #[test]
+    fn test_fixed_sequence_inside_comment() {
+        // A comment running through the sequence field is split around it
+        let code = format!("{:<72}{}\n*/ X;", " /* long", "SEQ00001");
+        let tokens = tokenize_fixed_tokens(&code, 73);
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.token_type).collect();
+        
+        assert_eq!(&types[..4], &[TokenType::Whitespace, TokenType::Comment, TokenType::SequenceNumber, TokenType::Comment]);
+        assert_eq!(tokens[2].text, "SEQ00001");
+    }
+    
+    #[test]
+    fn test_fixed_cr_line_endings() {
+        // A lone CR ends a card just like LF or CRLF
+        assert_eq!(tokenize_fixed("DCL X;\rY = 1;\r", 4), [
+            TokenType::Keyword as u32, 0, 3,
+            TokenType::SequenceNumber as u32, 3, 6,
+            TokenType::Newline as u32, 6, 7,
+            TokenType::Identifier as u32, 7, 8,
+            TokenType::Whitespace as u32, 8, 9,
+            TokenType::Operator as u32, 9, 10,
+            TokenType::SequenceNumber as u32, 10, 13,
+            TokenType::Newline as u32, 13, 14,
+        ]);
+        let code = format!("{:<72}{}\r{:<72}{}\r\n X;", " A = 1;", "SEQ00001", " B = 2;", "SEQ00002");
+        let seq: Vec<String> = tokenize_fixed_tokens(&code, 73)
+            .into_iter()
+            .filter(|t| t.token_type == TokenType::SequenceNumber)
+            .map(|t| t.text)
+            .collect();
+        assert_eq!(seq, ["SEQ00001", "SEQ00002"]);
+        
+        // A range widens to its own CR-delimited line, not the whole file
+        let code = "X = 1;\rY = 2;\rZ = 3;";
+        assert_eq!(tokenize_range(code, 9, 9), tokenize_line(code, 1));
+        assert_eq!(line_bounds("A;\r\nB;", 3, 3), (0, 4));
+    }
+    
+    #[test]
+    fn test_format_margins() {
+        let options = FormatOptions { left_margin: 2, right_margin: 72, tab_width: 1 };
+        let code = format!("*{:<71}{}\n*X = A * B;", " DCL X;", "12345678");
+        let tokens = tokenize_with_options(&code, &options);
+        
+        // Column 1 is carriage control, not an operator
+        assert_eq!((tokens[0].text.as_str(), tokens[0].token_type), ("*", TokenType::Whitespace));
+        let second_line: Vec<&Token> = tokens.iter().filter(|t| t.line == 1).collect();
+        assert_eq!(second_line[0].token_type, TokenType::Whitespace);
+        assert_eq!(second_line[1].text, "X");
+        
+        // The `*` inside the significant region is still an operator
+        let ops: Vec<&Token> = second_line.iter().copied().filter(|t| t.token_type == TokenType::Operator).collect();
+        assert_eq!(ops.iter().map(|t| t.text.as_str()).collect::<Vec<_>>(), ["=", "*"]);
+        
+        let seq = tokens.iter().find(|t| t.token_type == TokenType::SequenceNumber).unwrap();
+        assert_eq!(seq.text, "12345678");
+        
+        // Defaults reproduce free-format lexing
+        let free = tokenize_flat_with_options(&code, &FormatOptions::default());
+        assert_eq!(free, tokenize_flat(&code));
+    }
+    
+    #[test]
+    fn test_pseudovariables() {
+        assert_eq!(significant_types("SUBSTR(X,1,2)='AB';")[0], ("SUBSTR".to_string(), TokenType::Pseudovariable));
+        assert_eq!(significant_types("Y=SUBSTR(X,1,2);")[2], ("SUBSTR".to_string(), TokenType::Builtin));
+        assert_eq!(flat_type_of("SUBSTR(S,1,2) = 'A';", "SUBSTR"), Some(TokenType::Pseudovariable));
+        
+        // Multiple targets, labels, THEN clauses and argument-less ONCHAR
+        let t = significant_types("L: A, UNSPEC(B) = SUBSTR(C, 1); IF X THEN ONCHAR = '0';");
+        assert_eq!(t[4], ("UNSPEC".to_string(), TokenType::Pseudovariable));
+        assert_eq!(t[9], ("SUBSTR".to_string(), TokenType::Builtin));
+        assert_eq!(t[19], ("ONCHAR".to_string(), TokenType::Pseudovariable));
+        
+        // A comparison in an IF condition is not an assignment target
+        assert_eq!(significant_types("IF SUBSTR(S, 1, 1) = 'A' THEN X = 1;")[1].1, TokenType::Builtin);
+        // Builtins that are never pseudovariables are left alone
+        assert_eq!(significant_types("LENGTH(S) = 1;")[0].1, TokenType::Builtin);
+    }
+    
+    #[test]
+    fn test_is_blank_or_comment() {
+        assert!(is_blank_or_comment(""));
+        assert!(is_blank_or_comment("   \t\r\n\n"));
+        assert!(is_blank_or_comment("  /* just a note */\n"));
+        assert!(is_blank_or_comment("/* a */ /* b\n c */"));
+        assert!(is_blank_or_comment("/* unterminated"));
+        assert!(!is_blank_or_comment("  DCL X FIXED; /* c */"));
+        assert!(!is_blank_or_comment("/* c */ ;"));
+        assert!(!is_blank_or_comment("\u{00A7}"));
+    }
+    
+    #[test]
+    fn test_first_error() {
+        let code = "X = 1;\nY = `cmd`;";
+        let tick = code.find('`').unwrap();
+        assert_eq!(first_error(code), Some((tick, tick + 1)));
+        assert_eq!(first_error("DCL X FIXED; /* ` in a comment */ S = '`';"), None);
+        assert_eq!(first_error(""), None);
+        
+        // Multi-byte and coalesced runs report the whole run, matching `tokenize`
+        let code = "A = 1; B = €€ + 2; ``";
+        let (start, end) = first_error(code).unwrap();
+        assert_eq!(&code[start..end], "€€");
+        let unknown = tokenize(code).into_iter().find(|t| t.token_type == TokenType::Unknown).unwrap();
+        assert_eq!((unknown.start, unknown.end), (start, end));
+    }
+    
+    #[test]
+    fn test_stray_characters() {
+        let tokens: Vec<(String, TokenType, usize, usize)> = tokenize("A \\ B")
+            .into_iter()
+            .map(|t| (t.text, t.token_type, t.start, t.end))
+            .collect();
+        assert_eq!(tokens, [
+            ("A".into(), TokenType::Identifier, 0, 1),
+            (" ".into(), TokenType::Whitespace, 1, 2),
+            ("\\".into(), TokenType::Unknown, 2, 3),
+            (" ".into(), TokenType::Whitespace, 3, 4),
+            ("B".into(), TokenType::Identifier, 4, 5),
+        ]);
+        
+        // `?`, `!` and `\` have no rule of their own: they fall through to the
+        // lexer's error path, so adjacent ones (with any other unmatched input)
+        // coalesce into one Unknown and no byte is ever dropped
+        let code = "X = ?; Y = !; P = 'C:\\DIR'; \\?!€ Z";
+        let tokens = tokenize(code);
+        let unknown: Vec<&str> = tokens.iter().filter(|t| t.token_type == TokenType::Unknown).map(|t| t.text.as_str()).collect();
+        assert_eq!(unknown, ["?", "!", "\\?!€"]);
+        assert_eq!(tokens.iter().map(|t| t.text.as_str()).collect::<String>(), code);
+    }
+    
+    #[test]
+    fn test_unknown_runs_coalesce() {
+        let code = "X = €€€€; \u{1}\u{2}\u{3}";
+        let unknown: Vec<(usize, usize)> = tokenize(code)
+            .iter()
+            .filter(|t| t.token_type == TokenType::Unknown)
+            .map(|t| (t.start, t.end))
+            .collect();
+        assert_eq!(unknown, [(4, 16), (18, 21)]);
+        
+        let flat = tokenize_flat(code);
+        let unknown_flat: Vec<&[u32]> = flat.chunks(3).filter(|t| t[0] == TokenType::Unknown as u32).collect();
+        assert_eq!(unknown_flat, [&[TokenType::Unknown as u32, 4, 16][..], &[TokenType::Unknown as u32, 18, 21][..]]);
+        assert_eq!(flat.len() / 3, tokenize(code).len());
+    }
+    
+    #[test]
+    fn test_line_text() {
+        let code = "A = 1;\r\nB = 2;\nC = 3;";
+        assert_eq!(line_text(code, 0), Some("A = 1;"));
+        assert_eq!(line_text(code, 1), Some("B = 2;"));
+        assert_eq!(line_text(code, 2), Some("C = 3;"));
+        assert_eq!(line_text(code, 3), None);
+        assert_eq!(line_text("X;\n", 1), Some(""));
+        assert_eq!(line_text("", 0), Some(""));
+        assert_eq!(line_text("X;\rY;", 1), Some("Y;"));
+        
+        assert_eq!(line_of_offset(code, 0), 0);
+        assert_eq!(line_of_offset(code, 6), 0);
+        // The LF of a CRLF still ends line 0
+        assert_eq!(line_of_offset(code, 7), 0);
+        assert_eq!(line_of_offset(code, 8), 1);
+        let last = code.find('C').unwrap();
+        assert_eq!(line_of_offset(code, last + 3), 2);
+        assert_eq!(line_of_offset(code, code.len()), 2);
+        assert_eq!(line_of_offset(code, code.len() + 5), 2);
+        // Every token's line agrees
+        for token in tokenize(code) {
+            assert_eq!(line_of_offset(code, token.start), token.line, "{:?}", token);
+        }
+    }
+    
+    #[test]
+    fn test_tokenize_line() {
+        let code = "DCL X FIXED;\n/* one\ntwo */ X = 1;\nPUT LIST(X);";
+        let flat = tokenize_flat(code);
+        let within = |start: usize, end: usize| -> Vec<u32> {
+            flat.chunks(3)
+                .filter(|t| t[1] as usize >= start && t[2] as usize <= end)
+                .flatten()
+                .copied()
+                .collect()
+        };
+        
+        // Line 0, and the last line without a trailing newline
+        assert_eq!(tokenize_line(code, 0), within(0, 13));
+        assert_eq!(tokenize_line(code, 3), within(34, code.len()));
+        
+        // A middle line opening inside a comment starts with the comment's tail
+        let middle = tokenize_line(code, 2);
+        assert_eq!(&middle[..3], &[TokenType::Comment as u32, 20, 26]);
+        assert_eq!(&middle[3..], &within(26, 34)[..]);
+        
+        assert!(tokenize_line(code, 4).is_empty());
+    }
+    
+    #[test]
+    fn test_concat_groups() {
+        let code = "S = 'AB' || 'CD' || 'EF';";
+        let groups = concat_groups(code);
+        assert_eq!(groups.len(), 1);
+        let group = &groups[0];
+        let texts: Vec<&str> = group.operands.iter().map(|&(start, end)| &code[start..end]).collect();
+        assert_eq!(texts, ["'AB'", "'CD'", "'EF'"]);
+        assert_eq!(&code[group.start..group.end], "'AB' || 'CD' || 'EF'");
+        
+        // Comments and line breaks between operands; bit strings and numbers count
+        let code = "B = '01'B ||/* pad */\n  '1'B; N = 1 || 2;";
+        let spans: Vec<&str> = concat_groups(code).iter().map(|g| &code[g.start..g.end]).collect();
+        assert_eq!(spans, ["'01'B ||/* pad */\n  '1'B", "1 || 2"]);
+        
+        // A variable operand breaks the run; single constants form no group
+        assert!(concat_groups("S = 'A' || X || 'B'; T = 'C';").is_empty());
+        assert!(concat_groups("S = 'A' | 'B';").is_empty());
+    }
+    
+    #[test]
+    fn test_tokenize_members() {
+        let code = "./ ADD NAME=A\nDCL X; /* open\n./ ADD NAME=B,LEVEL=01\nY = 1;\n";
+        let members = tokenize_members(code, r"\./ ADD NAME=(\w+)").unwrap();
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].name.as_deref(), Some("A"));
+        assert_eq!(members[1].name.as_deref(), Some("B"));
+        
+        // The unterminated comment stops at the boundary
+        let last = members[0].tokens.last().unwrap();
+        assert_eq!((last.token_type, last.text.as_str()), (TokenType::Comment, "/* open\n"));
+        assert_eq!(members[1].tokens[0].text, "Y");
+        assert_eq!(members[1].tokens[0].token_type, TokenType::Identifier);
+        // Lines restart per member, offsets stay global
+        assert_eq!(members[1].tokens[0].line, 0);
+        let y = &members[1].tokens[0];
+        assert_eq!(&code[y.start..y.end], "Y");
+        
+        // Leading text is an unnamed member; a `name` group wins over group 1
+        let members = tokenize_members("X;\r\n(++) MEMBER2\r\nY;\r\n", r"(\(\+\+\))\s*(?<name>\S+)?").unwrap();
+        let names: Vec<_> = members.iter().map(|m| m.name.as_deref()).collect();
+        assert_eq!(names, [None, Some("MEMBER2")]);
+        // Patterns are anchored at the line start
+        assert_eq!(tokenize_members("X; ./ ADD NAME=A\n", r"\./").unwrap().len(), 1);
+        assert_eq!(tokenize_members("++\nX;", r"\+\+").unwrap()[0].name, None);
+        assert!(tokenize_members("", r"\./").unwrap().is_empty());
+        assert!(tokenize_members("X;", "(").is_err());
+    }
+    
+    #[test]
+    fn test_comment_directives() {
+        let code = "/*@SUPPRESS SIZE*/\nX = 1; /* plain note */ Y = '/*@NOT*/'; /*  @ NOLINT\n */";
+        let directives = comment_directives(code, "@");
+        let found: Vec<(&str, &str)> = directives.iter().map(|d| (d.text.as_str(), &code[d.start..d.end])).collect();
+        assert_eq!(found, [("SUPPRESS SIZE", "/*@SUPPRESS SIZE*/"), ("NOLINT", "/*  @ NOLINT\n */")]);
+        
+        let nolint = comment_directives("A; /* NOLINT */ /* nolint */", "NOLINT");
+        assert_eq!(nolint.len(), 1);
+        assert_eq!(nolint[0].text, "");
+    }
+    
+    #[test]
+    fn test_qualified_references() {
+        let code = "PAYROLL.EMPLOYEE.NAME = 'X'; A . /* c */ B = 3.14 + X.5;";
+        let refs = qualified_references(code);
+        assert_eq!(refs.len(), 2);
+        assert_eq!(refs[0].components, ["PAYROLL", "EMPLOYEE", "NAME"]);
+        assert_eq!(&code[refs[0].start..refs[0].end], "PAYROLL.EMPLOYEE.NAME");
+        assert_eq!(refs[1].components, ["A", "B"]);
+        assert_eq!(&code[refs[1].start..refs[1].end], "A . /* c */ B");
+        
+        // Decimal points belong to numbers
+        assert!(qualified_references("X = 3.14;").is_empty());
+        assert_eq!(significant("3.14")[0].token_type, TokenType::Number);
+    }
+    
+    #[test]
+    fn test_declares() {
+        let items = declares("DCL (A, B, C) FIXED BIN;");
+        assert_eq!(items.iter().map(|d| d.name.as_str()).collect::<Vec<_>>(), ["A", "B", "C"]);
+        assert!(items.iter().all(|d| d.attributes == ["FIXED", "BIN"] && d.level.is_none()));
+        
+        let code = "DECLARE 1 REC, 2 FIELD CHAR(10), 2 (X FLOAT, Y) STATIC; X = 1;";
+        let items = declares(code);
+        let summary: Vec<(&str, Option<u32>, Vec<&str>)> = items
+            .iter()
+            .map(|d| (d.name.as_str(), d.level, d.attributes.iter().map(String::as_str).collect()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("REC", Some(1), vec![]),
+                ("FIELD", Some(2), vec!["CHAR(10)"]),
+                ("X", Some(2), vec!["FLOAT", "STATIC"]),
+                ("Y", Some(2), vec!["STATIC"]),
+            ]
+        );
+        assert_eq!(&code[items[1].start..items[1].end], "FIELD");
+        
+        // Dimensions and nested argument lists stay whole
+        let items = declares("DCL T(10) CHAR(5) INIT((10)(1)'A'), P PTR;");
+        assert_eq!(items[0].attributes, ["(10)", "CHAR(5)", "INIT((10)(1)'A')"]);
+        assert_eq!((items[1].name.as_str(), items[1].attributes.as_slice()), ("P", &["PTR".to_string()][..]));
+    }
+    
+    #[test]
+    fn test_format_items() {
+        let t = significant_types("PUT EDIT(A)(F(5));");
+        assert_eq!(t[3], ("A".to_string(), TokenType::Identifier));
+        assert_eq!(t[6], ("F".to_string(), TokenType::FormatItem));
+        assert_eq!(t[8], ("5".to_string(), TokenType::Number));
+        assert_eq!(flat_type_of("PUT EDIT(A)(F(5));", "F"), Some(TokenType::FormatItem));
+        // Arguments are expressions, not part of the code
+        let t = significant_types("PUT EDIT(S)(A(LENGTH(S)));");
+        assert_eq!(t[8], ("LENGTH".to_string(), TokenType::Builtin));
+        
+        // Data/format pairs, iteration factors, nested groups and SKIP
+        let code = "PUT FILE(OUT) EDIT(X, Y)(A, F(W,2), 3 X(1)) (Z)((2)(SKIP, A(5)));";
+        let formats: Vec<String> = significant_types(code).into_iter().filter(|t| t.1 == TokenType::FormatItem).map(|t| t.0).collect();
+        assert_eq!(formats, ["A", "F", "X", "SKIP", "A"]);
+        assert_eq!(significant_types(code).iter().filter(|t| t.0 == "W").map(|t| t.1).collect::<Vec<_>>(), [TokenType::Identifier]);
+        
+        // Outside a format list F and A stay identifiers
+        assert_eq!(significant_types("F = A(1); GET LIST(F);")[0].1, TokenType::Identifier);
+        assert!(significant_types("F = A(1); GET LIST(F);").iter().all(|t| t.1 != TokenType::FormatItem));
+    }
+    
+    #[test]
+    fn test_significant_neighbours() {
+        let tokens = tokenize("  /* lead */ X = 1; /* tail */ \n");
+        let x = tokens.iter().position(|t| t.text == "X").unwrap();
+        let semi = tokens.iter().position(|t| t.text == ";").unwrap();
+        
+        // Leading trivia
+        assert_eq!(next_significant(&tokens, 0, true), Some(x));
+        assert_eq!(prev_significant(&tokens, x, true), None);
+        assert_eq!(prev_significant(&tokens, 0, true), None);
+        assert_eq!(tokens[next_significant(&tokens, x, true).unwrap()].text, "=");
+        
+        // Trailing trivia
+        assert_eq!(next_significant(&tokens, semi, true), None);
+        assert_eq!(prev_significant(&tokens, tokens.len() - 1, true), Some(semi));
+        assert_eq!(next_significant(&tokens, usize::MAX, true), None);
+        assert_eq!(prev_significant(&tokens, usize::MAX, true), Some(semi));
+    }
+    
+    #[test]
+    fn test_tokenize_semantic_modifiers() {
+        let modifiers_of = |code: &str, text: &str| -> Vec<u32> {
+            tokenize_semantic(code).into_iter().filter(|t| t.text == text).map(|t| t.modifiers).collect()
+        };
+        assert_eq!(modifiers_of("DCL X FIXED; X=1;", "X"), [MODIFIER_DECLARATION, 0]);
+        assert_eq!(modifiers_of("X=1;", "X"), [0]);
+        assert_eq!(modifiers_of("DCL (A, B) FIXED;", "B"), [MODIFIER_DECLARATION]);
+        
+        assert_eq!(modifiers_of("L: Y = SUBSTR(S, 1) || DATE;", "L"), [MODIFIER_LABEL]);
+        assert_eq!(modifiers_of("L: Y = SUBSTR(S, 1) || DATE;", "SUBSTR"), [MODIFIER_BUILTIN_CALL]);
+        assert_eq!(modifiers_of("L: Y = SUBSTR(S, 1) || DATE;", "DATE"), [0]);
+    }
+    
+    #[test]
+    fn test_scoped_tokens() {
+        let tokens = scoped_tokens("/* c */ ENTRY; S = \"x\";");
+        assert_eq!((tokens[0].text.as_str(), tokens[0].scope), ("/* c */", "comment.block.pli"));
+        assert_eq!((tokens[2].text.as_str(), tokens[2].scope), ("ENTRY", "keyword.pli"));
+        assert_eq!(tokens.last().map(|t| t.scope), Some("punctuation.pli"));
+        assert!(tokens.iter().any(|t| t.scope == "string.quoted.double.pli"));
+        
+        for &token_type in TokenType::ALL {
+            assert!(textmate_scope(token_type).ends_with(".pli"), "{:?}", token_type);
+        }
+    }
+    
+    #[test]
+    fn test_pygments_tokens() {
+        let code = "/* c */ DCL X;";
+        let tokens = pygments_tokens(code);
+        assert_eq!(tokens[0], ("Token.Comment.Multiline".to_string(), "/* c */".to_string()));
+        assert_eq!(tokens[2], ("Token.Keyword".to_string(), "DCL".to_string()));
+        assert_eq!(tokens[4].0, "Token.Name");
+        assert_eq!(tokens.iter().map(|(_, text)| text.as_str()).collect::<String>(), code);
+        
+        for &token_type in TokenType::ALL {
+            assert!(pygments_token(token_type).starts_with("Token."), "{:?}", token_type);
+        }
+    }
+    
+    #[test]
+    fn test_line_endings() {
+        let newlines = |code: &str| -> Vec<String> {
+            tokenize(code).into_iter().filter(|t| t.token_type == TokenType::Newline).map(|t| t.text).collect()
+        };
+        assert_eq!(newlines("X = 1;\r\n"), ["\r\n"]);
+        assert_eq!(newlines("X = 1;\rY = 2;"), ["\r"]);
+        assert_eq!(newlines("A;\n\r\nB;\r\rC;"), ["\n", "\r\n", "\r", "\r"]);
+        
+        // Whitespace never swallows a CR, and lone CRs advance the line count
+        let tokens = tokenize("X = 1; \r\nY = 2;\rZ;");
+        assert!(tokens.iter().all(|t| t.token_type != TokenType::Whitespace || !t.text.contains('\r')));
+        let z = tokens.iter().find(|t| t.text == "Z").unwrap();
+        assert_eq!((z.line, z.column), (2, 0));
+        
+        // Line-based helpers agree with those line numbers
+        let code = "X = 1;\r/* a\rb */ Z;";
+        let lines = tokens_by_line(code);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[2].iter().map(|t| t.text.as_str()).collect::<Vec<_>>(), ["b */", " ", "Z", ";"]);
+        assert_eq!(&tokenize_line(code, 2)[..3], &[TokenType::Comment as u32, 12, 16]);
+    }
+    
+    #[test]
+    fn test_tokens_equivalent() {
+        let a = "DCL TOTAL FIXED DECIMAL(7,2) INIT(0);";
+        let b = "dcl  total\n    fixed decimal ( 7 , 2 )  /* running sum */\n    init(0) ;";
+        assert!(tokens_equivalent(a, b));
+        assert!(tokens_equivalent(b, a));
+        
+        // A renamed identifier, an extra token or an edited string are real changes
+        assert!(!tokens_equivalent(a, "DCL COUNT FIXED DECIMAL(7,2) INIT(0);"));
+        assert!(!tokens_equivalent(a, "DCL TOTAL FIXED DECIMAL(7,2);"));
+        assert!(!tokens_equivalent("S = 'abc';", "S = 'ABC';"));
+        assert!(tokens_equivalent("", "  /* nothing */\n"));
+    }
+    
+    #[test]
+    fn test_normalized_hash() {
+        let plain = normalized_hash("DCL X FIXED;");
+        assert_eq!(normalized_hash("dcl   x   fixed ;  /* c */"), plain);
+        assert_eq!(normalized_hash("DCL\n  Y FIXED; "), plain);
+        assert_ne!(normalized_hash("DCL X FLOAT;"), plain);
+        assert_ne!(normalized_hash("DCL X FIXED BIN;"), plain);
+        
+        // Raw names: case still folds, renaming does not
+        assert_eq!(normalized_hash_with("dcl x fixed;", false), normalized_hash_with("DCL X FIXED;", false));
+        assert_ne!(normalized_hash_with("DCL Y FIXED;", false), normalized_hash_with("DCL X FIXED;", false));
+        
+        // String contents are significant, and token boundaries too
+        assert_ne!(normalized_hash("S = 'a';"), normalized_hash("S = 'A';"));
+        assert_ne!(normalized_hash_with("AB = 1;", false), normalized_hash_with("A B = 1;", false));
+    }
+    
+    #[test]
+    fn test_line_fingerprints() {
+        let before = line_fingerprints("DCL X FIXED;\nX = 1;\nY = 2;\nPUT LIST(X);");
+        let after = line_fingerprints("DCL X FIXED;\nX = 12345 + Z;\nY = 2;\nPUT LIST(X);");
+        assert_eq!(before.len(), 4);
+        assert_ne!(before[1], after[1]);
+        assert_eq!((before[0], before[2], before[3]), (after[0], after[2], after[3]));
+        
+        // The same line elsewhere in a file hashes the same; comment state does count
+        assert_eq!(line_fingerprints("A;\nX = 1;")[1], line_fingerprints("X = 1;")[0]);
+        let commented = line_fingerprints("DCL X FIXED;\nX = 1; /*\nY = 2;\nPUT LIST(X);");
+        assert_ne!(commented[2], before[2]);
+    }
+    
+    #[test]
+    fn test_tab_width() {
+        let options = FormatOptions { tab_width: 8, ..FormatOptions::default() };
+        // A tab in (1-based) column 1 puts X in column 9, 0-based column 8
+        let tokens: Vec<Token> = tokenize_with_options("\tX = 1;\n  \tY;", &options)
+            .into_iter()
+            .filter(|t| !t.token_type.is_trivia())
+            .collect();
+        assert_eq!((tokens[0].text.as_str(), tokens[0].column), ("X", 8));
+        assert_eq!((tokens[1].text.as_str(), tokens[1].column), ("=", 10));
+        assert_eq!((tokens[4].text.as_str(), tokens[4].line, tokens[4].column), ("Y", 1, 8));
+        
+        // Without expansion a tab is one column
+        let plain = tokenize_with_options("\tX = 1;", &FormatOptions::default());
+        assert_eq!(plain[1].column, 1);
+        
+        // Margins are measured in expanded columns: tabs push Z past column 72
+        let card = FormatOptions { tab_width: 8, ..FormatOptions::card() };
+        let code = format!(" X = 1;{}Z", "\t".repeat(9));
+        let tokens = tokenize_with_options(&code, &card);
+        let seq = tokens.iter().find(|t| t.token_type == TokenType::SequenceNumber).unwrap();
+        assert_eq!((seq.text.as_str(), seq.column), ("Z", 72));
+    }
+    
+    #[test]
+    fn test_tokens_by_line_splits_comments() {
+        let code = "X = 1; /* one\ntwo\nthree */ Y = 2;";
+        let lines = tokens_by_line(code);
+        assert_eq!(lines.len(), 3);
+        
+        let comments: Vec<&Token> = lines.iter().flatten().filter(|t| t.token_type == TokenType::Comment).collect();
+        assert_eq!(comments.iter().map(|t| t.text.as_str()).collect::<Vec<_>>(), ["/* one\n", "two\n", "three */"]);
+        assert_eq!(comments.iter().map(|t| t.line).collect::<Vec<_>>(), [0, 1, 2]);
+        for t in &comments {
+            assert_eq!(&code[t.start..t.end], t.text);
+        }
+        assert_eq!(lines[1].len(), 1);
+        assert_eq!(comments[2].column, 0);
+        
+        // Each bucket reassembles its line
+        for (i, line) in code.split_inclusive('\n').enumerate() {
+            let text: String = lines[i].iter().map(|t| t.text.as_str()).collect();
+            assert_eq!(text, line);
+        }
+    }
+    
+    #[test]
+    fn test_tokens_by_line_trailing_blank_lines() {
+        let lines = tokens_by_line("X;\n\n\n");
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1].len(), 1);
+        assert_eq!(lines[1][0].token_type, TokenType::Newline);
+        assert_eq!(lines[2][0].token_type, TokenType::Newline);
+        assert!(lines[3].is_empty());
+    }
+    
+    #[test]
+    fn test_token_stream_large_input() {
+        let code = "DCL X FIXED BIN(31); /* counter */\nX = X + 1;\n".repeat(20_000);
+        
+        let mut count = 0;
+        let mut newlines = 0;
+        let mut last_end = 0;
+        for token in TokenStream::new(&code) {
+            assert_eq!(token.start, last_end);
+            last_end = token.end;
+            count += 1;
+            if token.token_type == TokenType::Newline {
+                newlines += 1;
+            }
+        }
+        
+        assert_eq!(last_end, code.len());
+        assert_eq!(newlines, 40_000);
+        assert_eq!(count, 20_000 * 25);
+    }
+    
+    #[test]
+    fn test_tokenize_borrowed_zero_copy() {
+        let code = "MAIN: PROC; DCL S CHAR(10) INIT('IT''S'); /* x */ PUT LIST(S); END;\n".repeat(100);
+        assert!(code.len() > 4096);
+        
+        let refs = tokenize_borrowed(&code);
+        let buffer = code.as_bytes().as_ptr_range();
+        for token in &refs {
+            // Slices point into the original buffer, not into copies
+            assert!(buffer.contains(&token.text.as_ptr()));
+            assert_eq!(token.text.as_ptr(), code[token.start..].as_ptr());
+            assert_eq!(token.text.len(), token.end - token.start);
+        }
+        
+        let owned = tokenize(&code);
+        assert_eq!(owned.len(), refs.len());
+        assert!(owned.iter().zip(&refs).all(|(o, r)| o.text == r.text && o.token_type == r.token_type));
+        assert_eq!(refs[0].token_type, TokenType::Label);
+    }
+    
+    #[test]
+    fn test_matching_parentheses() {
+        let code = "X = (A + (B * C)) + D(1);";
+        let outer = code.find('(').unwrap();
+        let inner = code[outer + 1..].find('(').unwrap() + outer + 1;
+        
+        assert_eq!(find_matching_bracket(code, outer), Some(code.find("))").unwrap() + 1));
+        assert_eq!(find_matching_bracket(code, inner), Some(code.find("))").unwrap()));
+        assert_eq!(find_matching_bracket(code, code.find("))").unwrap()), Some(inner));
+        assert_eq!(find_matching_bracket(code, 0), None);
+        assert_eq!(find_matching_bracket("X = (A;", 4), None);
+    }
+    
+    #[test]
+    fn test_matching_blocks() {
+        let code = "SELECT (X);\n WHEN (1) DO;\n  Y = 1;\n END;\n OTHERWISE;\nEND;";
+        let select = code.find("SELECT").unwrap();
+        let do_kw = code.find("DO").unwrap();
+        let inner_end = code.find("END").unwrap();
+        let outer_end = code.rfind("END").unwrap();
+        
+        assert_eq!(find_matching_bracket(code, do_kw), Some(inner_end));
+        assert_eq!(find_matching_bracket(code, inner_end + 1), Some(do_kw));
+        assert_eq!(find_matching_bracket(code, select), Some(outer_end));
+        assert_eq!(find_matching_bracket(code, outer_end), Some(select));
+    }
+    
+    #[test]
+    fn test_matching_labelled_end() {
+        // END MAIN closes the procedure and the still-open DO inside it
+        let code = "MAIN: PROC;\n DO I = 1 TO 3;\n  X = I;\nEND MAIN;";
+        let proc_kw = code.find("PROC").unwrap();
+        let do_kw = code.find("DO").unwrap();
+        let end = code.find("END").unwrap();
+        
+        assert_eq!(find_matching_bracket(code, end), Some(proc_kw));
+        assert_eq!(find_matching_bracket(code, proc_kw), Some(end));
+        assert_eq!(find_matching_bracket(code, do_kw), Some(end));
+    }
+    
+    #[test]
+    fn test_tokenize_with_depth() {
+        let depths: Vec<(String, u32)> = tokenize_with_depth("A(B(C))").into_iter().map(|(t, d)| (t.text, d)).collect();
+        assert_eq!(depths, [
+            ("A".into(), 0), ("(".into(), 1), ("B".into(), 1), ("(".into(), 2), ("C".into(), 2), (")".into(), 2), (")".into(), 1),
+        ]);
+        
+        // Brackets in strings and comments don't count
+        let code = "X = F('(', /* ) */ 1);";
+        let depths = paren_depths(code);
+        assert_eq!(depths.len(), tokenize_flat(code).len() / 3);
+        assert_eq!(depths.last(), Some(&0));
+        assert_eq!(depths.iter().max(), Some(&1));
+        
+        // Unbalanced input never underflows
+        assert_eq!(paren_depths("))(X"), [0, 0, 1, 1]);
+        assert_eq!(paren_depths("((("), [1, 2, 3]);
+        assert!(tokenize_with_depth("").is_empty());
+    }
+    
+    #[test]
+    fn test_token_at() {
+        let code = "L: DECLARE X;\nY=X<=1;";
+        let at = |offset: usize| token_at(code, offset).map(|t| (t.text, t.token_type, t.start));
+        
+        assert_eq!(at(5), Some(("DECLARE".into(), TokenType::Keyword, 3)));
+        assert_eq!(at(0), Some(("L".into(), TokenType::Label, 0)));
+        // Between `=` and `X` the token starting there wins
+        let eq = code.find("=X").unwrap();
+        assert_eq!(at(eq + 1), Some(("X".into(), TokenType::Identifier, eq + 1)));
+        assert_eq!(at(eq + 2), Some(("<=".into(), TokenType::Operator, eq + 2)));
+        assert_eq!(at(eq + 3), Some(("<=".into(), TokenType::Operator, eq + 2)));
+        assert_eq!(at(code.len() - 1).map(|t| t.0), Some(";".into()));
+        
+        assert_eq!(at(code.len()), None);
+        assert_eq!(at(code.len() + 10), None);
+        assert!(token_at("", 0).is_none());
+    }
+    
+    #[test]
+    fn test_check_block_balance() {
+        let code = "P: PROC;\n  DO I = 1 TO 3;\n    X = I;\n  IF X THEN DO; END;\nEND P;";
+        // The loop has no END of its own: END P closes it together with the PROC
+        assert!(check_block_balance(code).is_empty());
+        
+        let missing = check_block_balance("P: PROC;\n  DO;\n  X = 1;\n  END;\n  BEGIN;\n");
+        assert_eq!(missing.len(), 2);
+        assert_eq!((missing[0].line, missing[0].message.as_str()), (0, "missing END for PROC at line 1"));
+        assert_eq!(missing[1].message, "missing END for BEGIN at line 5");
+        
+        let code = "DO;\nEND;\nEND;";
+        let extra = check_block_balance(code);
+        assert_eq!(extra.len(), 1);
+        assert_eq!((extra[0].start, extra[0].end, extra[0].line), (9, 12, 2));
+        assert!(extra[0].message.starts_with("END at line 3"));
+        
+        let nested = "MAIN: PROC OPTIONS(MAIN);\n  SELECT (X);\n    WHEN (1) BEGIN; END;\n  END;\n  SUB: PROC; END SUB;\nEND MAIN;";
+        assert!(check_block_balance(nested).is_empty());
+    }
+    
+    #[test]
+    fn test_indent_levels() {
+        let code = "MAIN: PROC;\n  DCL I FIXED;\n  DO I = 1 TO 10;\n    PUT LIST(I);\n\n  END;\n  X = 1; END MAIN;\n";
+        assert_eq!(indent_levels(code), [0, 1, 1, 2, 2, 1, 1, 0]);
+        
+        // Multiple closure, a block closed on its own line, and one never closed
+        let code = "A: DO;\nDO;\nEND A;\nDO; END;\nBEGIN;\n/* still\n   open */\nY;";
+        assert_eq!(indent_levels(code), [0, 1, 0, 0, 0, 1, 1, 1]);
+        
+        assert_eq!(indent_levels(""), [0]);
+    }
+    
+    #[test]
+    fn test_folding_ranges() {
+        let code = "\
+/* Sums the matrix
+   row by row
+   and prints
+   the total */
+SUM: PROC;
+  DO I = 1 TO N;
+    DO J = 1 TO M;
+      T = T + A(I, J);
+    END;
+  END;
+  IF T > 0 THEN DO; PUT LIST(T); END;
+END SUM;";
+        let ranges = folding_ranges(code);
+        
+        assert_eq!(ranges, vec![
+            FoldRange { start_line: 0, end_line: 3, kind: FoldKind::Comment },
+            FoldRange { start_line: 4, end_line: 11, kind: FoldKind::Block },
+            FoldRange { start_line: 5, end_line: 9, kind: FoldKind::Block },
+            FoldRange { start_line: 6, end_line: 8, kind: FoldKind::Block },
+        ]);
+    }
+    
+    #[test]
+    fn test_statement_ranges() {
+        let code = "A = 1; B = ';';\n/* ; */ PUT LIST(A,\n   B);\nC = 2";
+        let spans: Vec<(&str, usize, usize)> = statement_ranges(code)
+            .iter()
+            .map(|r| (&code[r.start..r.end], r.start_line, r.end_line))
+            .collect();
+        assert_eq!(spans, [
+            ("A = 1;", 0, 0),
+            ("B = ';';", 0, 0),
+            ("PUT LIST(A,\n   B);", 1, 2),
+            ("C = 2", 3, 3),
+        ]);
+    }
+    
+    #[test]
+    fn test_statement_ranges_comment_modes() {
+        let code = "X = 1 /* mid */ + 2; /* lead */ Y = 3;";
+        let texts = |comments_are_trivia: bool| -> Vec<&str> {
+            statement_ranges_with(code, comments_are_trivia).iter().map(|r| &code[r.start..r.end]).collect()
+        };
+        assert_eq!(texts(true), ["X = 1 /* mid */ + 2;", "Y = 3;"]);
+        assert_eq!(texts(false), ["X = 1 /* mid */ + 2;", "/* lead */ Y = 3;"]);
+        
+        let tokens = tokenize(code);
+        let one = tokens.iter().position(|t| t.text == "1").unwrap();
+        assert_eq!(tokens[next_significant(&tokens, one, true).unwrap()].text, "+");
+        assert_eq!(tokens[next_significant(&tokens, one, false).unwrap()].text, "/* mid */");
+        let plus = tokens.iter().position(|t| t.text == "+").unwrap();
+        assert_eq!(prev_significant(&tokens, plus, false), Some(one + 2));
+    }
+    
+    #[test]
+    fn test_outline() {
+        let code = "\
+MAIN: PROC OPTIONS(MAIN);
+  CALL SUB;
+  SUB: PROC;
+    RETRY: X = X + 1;
+  END SUB;
+  ALT: ENTRY;
+END MAIN;
+AFTER: RETURN;";
+        let symbols = outline(code);
+        let summary: Vec<(&str, SymbolKind, Option<usize>)> = symbols
+            .iter()
+            .map(|s| (s.name.as_str(), s.kind, s.parent))
+            .collect();
+        assert_eq!(summary, [
+            ("MAIN", SymbolKind::Procedure, None),
+            ("SUB", SymbolKind::Procedure, Some(0)),
+            ("RETRY", SymbolKind::Label, Some(1)),
+            ("ALT", SymbolKind::Entry, Some(0)),
+            ("AFTER", SymbolKind::Label, None),
+        ]);
+        
+        assert_eq!(&code[symbols[0].start..symbols[0].end], &code[..code.find("\nAFTER").unwrap()]);
+        assert!(code[symbols[1].start..symbols[1].end].ends_with("END SUB;"));
+        assert_eq!(&code[symbols[3].start..symbols[3].end], "ALT");
+    }
+    
+    #[test]
+    fn test_folding_preprocessor_groups() {
+        let code = "%DO;\n  %INCLUDE A;\n%END;\n/* one line */";
+        assert_eq!(folding_ranges(code), vec![
+            FoldRange { start_line: 0, end_line: 2, kind: FoldKind::Preprocessor },
+        ]);
+    }
+    
+    #[test]
+    fn test_not_sign_operators() {
+        let tokens = significant("IF A ¬= B THEN X = ¬Y;");
+        let texts: Vec<(&str, TokenType)> = tokens.iter().map(|t| (t.text.as_str(), t.token_type)).collect();
+        assert_eq!(texts[1..4], [("A", TokenType::Identifier), ("¬=", TokenType::Operator), ("B", TokenType::Identifier)]);
+        assert!(texts.contains(&("¬", TokenType::Operator)));
+        
+        let tokens = significant("A ^= B | ~C");
+        assert_eq!(tokens[1].text, "^=");
+        assert_eq!(tokens[1].token_type, TokenType::Operator);
+        assert_eq!(tokens[4].text, "~");
+        assert_eq!(tokens[4].token_type, TokenType::Operator);
+        assert!(tokens.iter().all(|t| t.token_type != TokenType::Unknown));
+    }
+    
+    #[test]
+    fn test_number_literals() {
+        for literal in ["'1010'B", "'1010'B1", "'F0'X", "'777'B3", "'3210'B2", "'1F'B4", "'1F'BX", "''B", "1.5E-3", "1.5F+2", "12", ".5", "1011B", "3I"] {
+            let tokens = tokenize(&format!("X = {};", literal));
+            let number = &tokens[4];
+            
+            assert_eq!(number.token_type, TokenType::Number, "{}", literal);
+            assert_eq!(number.text, literal);
+        }
+        
+        // Digits outside the radix don't make a bit string
+        let tokens = significant("X = '129'B3;");
+        assert_eq!(tokens[2].token_type, TokenType::String);
+        assert_eq!(tokens[3].text, "B3");
+    }
+    
+    #[test]
+    fn test_picture_strings() {
+        let tokens = significant("DCL A PIC'999V99', B PICTURE '$$,$$9', C CHAR(5) INIT('ZZ9');\nS = '999';");
+        let strings: Vec<(&str, TokenType)> = tokens
+            .iter()
+            .filter(|t| t.text.starts_with('\''))
+            .map(|t| (t.text.as_str(), t.token_type))
+            .collect();
+        
+        assert_eq!(strings, [
+            ("'999V99'", TokenType::Picture),
+            ("'$$,$$9'", TokenType::Picture),
+            ("'ZZ9'", TokenType::String),
+            ("'999'", TokenType::String),
+        ]);
+        // The editor's flat path sees the pictures too
+        assert_eq!(flat_type_of("DCL P PIC'99';", "'99'"), Some(TokenType::Picture));
+    }
+    
+    #[test]
+    fn test_tokenize_with_keywords() {
+        let tokens = tokenize_with_keywords("mymacro(X); MyMacro2 = MYMACRO;", vec!["MYMACRO".to_string()]);
+        let words: Vec<(&str, TokenType)> = tokens
+            .iter()
+            .filter(|t| t.token_type.is_word())
+            .map(|t| (t.text.as_str(), t.token_type))
+            .collect();
+        assert_eq!(words, [
+            ("mymacro", TokenType::Keyword),
+            ("X", TokenType::Identifier),
+            ("MyMacro2", TokenType::Identifier),
+            ("MYMACRO", TokenType::Keyword),
+        ]);
+        assert_eq!(tokens[0].canonical.as_deref(), Some("MYMACRO"));
+        
+        let flat = tokenize_flat_with_keywords("mymacro;", vec!["mymacro".to_string()]);
+        assert_eq!(flat[..3], [TokenType::Keyword as u32, 0, 7]);
+    }
+    
+    #[test]
+    fn test_context_sensitive_io() {
+        let io = LexOptions { context_sensitive_io: true, ..LexOptions::default() };
+        let words = |code: &str, options: &LexOptions| -> Vec<(String, TokenType)> {
+            tokenize_with_lex_options(code, options)
+                .into_iter()
+                .filter(|t| t.token_type.is_word())
+                .map(|t| (t.text, t.token_type))
+                .collect()
+        };
+        
+        assert_eq!(words("PUT SKIP LIST(X);", &io), [
+            ("PUT".into(), TokenType::KeywordIo),
+            ("SKIP".into(), TokenType::KeywordIo),
+            ("LIST".into(), TokenType::KeywordIo),
+            ("X".into(), TokenType::Identifier),
+        ]);
+        assert_eq!(words("LINE = 5;", &io), [("LINE".into(), TokenType::Identifier)]);
+        // Off by default
+        assert_eq!(words("LINE = 5;", &LexOptions::default()), [("LINE".into(), TokenType::KeywordIo)]);
+        
+        // The statement lasts until `;`, wherever its keyword sits
+        let code = "IF DATA > 0 THEN PUT PAGE DATA(DATA); ON ENDFILE(F) GET EDIT(LINE)(A(80)); COL = LINE;";
+        let types: Vec<TokenType> = words(code, &io).into_iter().map(|(_, t)| t).collect();
+        assert_eq!(types, [
+            TokenType::KeywordControl, TokenType::Identifier, TokenType::KeywordControl,
+            TokenType::KeywordIo, TokenType::KeywordIo, TokenType::KeywordIo, TokenType::KeywordIo,
+            TokenType::Keyword, TokenType::Identifier,
+            TokenType::KeywordIo, TokenType::KeywordIo, TokenType::KeywordIo,
+            TokenType::Identifier, TokenType::Identifier,
+        ]);
+        
+        let highlighter = Highlighter::new(Vec::new(), &FormatOptions::default(), &io);
+        assert_eq!(highlighter.tokenize("DATA = 1;")[0].token_type, TokenType::Identifier);
+    }
+    
+    #[test]
+    fn test_star_comment_cards() {
+        let cards = LexOptions { star_comment_col1: true, ..LexOptions::default() };
+        let code = "* comment card; A = B\n  ** indented too\nA = B * C;\r*\n*PROCESS OPT(2);";
+        let kinds = |options: &LexOptions| -> Vec<(String, TokenType)> {
+            tokenize_with_lex_options(code, options)
+                .into_iter()
+                .filter(|t| matches!(t.token_type, TokenType::Comment | TokenType::Operator | TokenType::Directive))
+                .map(|t| (t.text, t.token_type))
+                .collect()
+        };
+        
+        assert_eq!(kinds(&cards), [
+            ("* comment card; A = B".into(), TokenType::Comment),
+            ("** indented too".into(), TokenType::Comment),
+            ("=".into(), TokenType::Operator),
+            ("*".into(), TokenType::Operator),
+            ("*".into(), TokenType::Comment),
+            ("*PROCESS OPT(2);".into(), TokenType::Directive),
+        ]);
+        // Off by default: the same stars are operators
+        let standard = kinds(&LexOptions::default());
+        assert_eq!(standard[..3], [
+            ("*".into(), TokenType::Operator),
+            ("=".into(), TokenType::Operator),
+            ("**".into(), TokenType::Operator),
+        ]);
+        assert_eq!(standard.last().unwrap().1, TokenType::Directive);
+    }
+    
+    #[test]
+    fn test_tokenize_with_dialect() {
+        let word_types = |code: &str, dialect: Dialect| -> Vec<TokenType> {
+            tokenize_with_dialect(code, dialect)
+                .into_iter()
+                .filter(|t| t.token_type.is_word())
+                .map(|t| t.token_type)
+                .collect()
+        };
+        let code = "DCL H HANDLE(T);";
+        assert_eq!(word_types(code, Dialect::IbmEnterprise)[2], TokenType::KeywordType);
+        assert_eq!(word_types(code, Dialect::Ansi)[2], TokenType::Identifier);
+        assert_eq!(word_types(code, Dialect::Pli390)[2], TokenType::Identifier);
+        // The default dialect is what `tokenize` gives
+        let types: Vec<TokenType> = tokenize(code).into_iter().filter(|t| t.token_type.is_word()).map(|t| t.token_type).collect();
+        assert_eq!(word_types(code, Dialect::default()), types);
+        let code = "L: PUT EDIT(X)(F(5)); SUBSTR(S,1,2) = 'A'; ON ERROR X=1; DCL P PIC'99';";
+        assert_eq!(tokenize_flat_with_dialect(code, Dialect::default()), tokenize_flat(code));
+        
+        // Multitasking is IBM-only; Subset G also drops COMPLEX
+        let code = "WAIT(E); DCL Z COMPLEX;";
+        assert_eq!(word_types(code, Dialect::Pli390)[0], TokenType::Keyword);
+        assert_eq!(word_types(code, Dialect::Ansi)[0], TokenType::Identifier);
+        assert_eq!(word_types(code, Dialect::Ansi)[4], TokenType::KeywordType);
+        assert_eq!(word_types(code, Dialect::Subset)[4], TokenType::Identifier);
+        
+        let union = tokenize_with_dialect("UNION", Dialect::Ansi);
+        assert_eq!(union[0].canonical, None);
+        assert_eq!(tokenize_flat_with_dialect("UNION", Dialect::Ansi), [TokenType::Identifier as u32, 0, 5]);
+    }
+    
+    #[test]
+    fn test_line_comments_option() {
+        let texts = |tokens: Vec<Token>| -> Vec<(String, TokenType)> {
+            tokens
+                .into_iter()
+                .filter(|t| !matches!(t.token_type, TokenType::Whitespace | TokenType::Newline))
+                .map(|t| (t.text, t.token_type))
+                .collect()
+        };
+        let standard = texts(tokenize("A = B -- C"));
+        assert_eq!(standard[3..], [("-".into(), TokenType::Operator), ("-".into(), TokenType::Operator), ("C".into(), TokenType::Identifier)]);
+        assert_eq!(texts(tokenize_with_lex_options("A = B -- C", &LexOptions::default())), standard);
+        
+        let dialect = LexOptions { line_comments: true, ..LexOptions::default() };
+        let tokens = texts(tokenize_with_lex_options("A = -- trailing comment\r\nB = '--';", &dialect));
+        assert_eq!(tokens[..4], [
+            ("A".into(), TokenType::Identifier),
+            ("=".into(), TokenType::Operator),
+            ("-- trailing comment".into(), TokenType::Comment),
+            ("B".into(), TokenType::Identifier),
+        ]);
+        assert_eq!(tokens[5], ("'--'".into(), TokenType::String));
+        
+        let flat = tokenize_flat_with_lex_options("X -- C", &dialect);
+        assert_eq!(flat[6..], [TokenType::Comment as u32, 2, 6]);
+    }
+    
+    #[test]
+    fn test_tokenize_significant() {
+        let code = "DCL X FIXED BIN; /* counter */\n  L: X = 1;";
+        let tokens = tokenize_significant(code, false);
+        let texts: Vec<&str> = tokens.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(texts, ["DCL", "X", "FIXED", "BIN", ";", "L", ":", "X", "=", "1", ";"]);
+        assert!(tokens.iter().all(|t| code[t.start..t.end] == t.text));
+        assert_eq!((tokens[5].token_type, tokens[5].line, tokens[5].column), (TokenType::Label, 1, 2));
+        
+        let with_comments = tokenize_significant(code, true);
+        assert_eq!(with_comments.len(), tokens.len() + 1);
+        assert_eq!(with_comments[5].text, "/* counter */");
+        assert_eq!(with_comments[5].start, code.find("/*").unwrap());
+    }
+    
+    #[test]
+    fn test_tokenize_flat_tagged() {
+        let code = "DCL X; /* c */\n";
+        let plain = tokenize_flat(code);
+        let tagged = tokenize_flat_tagged(code);
+        assert_eq!(tagged.len(), plain.len());
+        
+        for (tagged, plain) in tagged.chunks(3).zip(plain.chunks(3)) {
+            let token_type = TokenType::from_code(plain[0]).unwrap();
+            assert_eq!(tagged[0] & TRIVIA_FLAG != 0, token_type.is_trivia(), "{:?}", token_type);
+            assert_eq!(tagged[0] & !TRIVIA_FLAG, plain[0]);
+            assert_eq!(tagged[1..], plain[1..]);
+        }
+        assert_eq!(tagged[0], TokenType::Keyword as u32);
+        assert_eq!(tagged[3], TokenType::Whitespace as u32 | 0x8000_0000);
+    }
+    
+    #[test]
+    fn test_tokenize_flat_limited() {
+        let code = "X = 1;\n".repeat(1000);
+        
+        let limited = tokenize_flat_limited(&code, Some(10));
+        assert!(limited.truncated());
+        let tokens = limited.tokens();
+        assert_eq!(tokens.len(), 11 * 3);
+        assert_eq!(tokens[..30], tokenize_flat(&code)[..30]);
+        // The tail picks up exactly where the last real token stopped
+        assert_eq!(tokens[30..], [TokenType::Unknown as u32, tokens[29], code.len() as u32]);
+        assert_eq!(tokens[29], 10);
+        
+        // A limit the input stays within changes nothing
+        let all = tokenize_flat(&code);
+        for max_tokens in [None, Some(all.len() / 3), Some(usize::MAX)] {
+            let limited = tokenize_flat_limited(&code, max_tokens);
+            assert!(!limited.truncated());
+            assert_eq!(limited.tokens(), all);
+        }
+        
+        let limited = tokenize_flat_limited("X", Some(0));
+        assert!(limited.truncated());
+        assert_eq!(limited.tokens(), [TokenType::Unknown as u32, 0, 1]);
+        assert!(!tokenize_flat_limited("", Some(0)).truncated());
+    }
+    
+    #[test]
+    fn test_minify() {
+        assert_eq!(minify("DCL   X   FIXED ;", false), "DCL X FIXED;");
+        assert_eq!(minify("A=B", false), "A=B");
+        assert_eq!(minify("  A  =  B  ;\n  C = 1 E 2;\n", false), "A=B;C=1 E 2;");
+        // Adjacent strings would read as one string with an embedded quote
+        assert_eq!(minify("S = 'A' 'B' || 'C';", false), "S='A' 'B'||'C';");
+        // Dropping a comment must not glue its neighbours together
+        assert_eq!(minify("X /* c */ Y  /* d */ = 1;", false), "X Y=1;");
+        assert_eq!(minify("X /* c */ Y  /* d */ = 1;", true), "X/* c */Y/* d */=1;");
+        // Strings keep their inner whitespace
+        assert_eq!(minify("PUT LIST ( 'A   B' ) ;", false), "PUT LIST('A   B');");
+        // Spaces are judged against what follows: 1E+2 would be one number
+        assert_eq!(minify("Y = 1 E + 2;", false), "Y=1 E+2;");
+    }
+    
+    #[test]
+    fn test_highlight_html() {
+        assert_eq!(
+            highlight_html("DCL X;\n", "pli-"),
+            "<span class=\"pli-keyword\">DCL</span> <span class=\"pli-identifier\">X</span>\
+             <span class=\"pli-punctuation\">;</span>\n"
+        );
+        let html = highlight_html("IF A < B THEN S = '<a&b>';", "pli-");
+        assert!(html.contains("<span class=\"pli-operator\">&lt;</span>"));
+        assert!(html.contains("<span class=\"pli-string\">'&lt;a&amp;b&gt;'</span>"));
+        // Whitespace runs are left exactly as written
+        assert!(highlight_html("A  =\t1;", "").starts_with("<span class=\"identifier\">A</span>  <span"));
+        assert_eq!(highlight_html("", "pli-"), "");
+    }
+    
+    #[test]
+    fn test_highlight_ansi() {
+        let strip = |s: &str| -> String {
+            let mut out = String::new();
+            let mut rest = s;
+            while let Some(at) = rest.find('\x1b') {
+                out.push_str(&rest[..at]);
+                rest = &rest[at + rest[at..].find('m').unwrap() + 1..];
+            }
+            out + rest
+        };
+        
+        let code = "L: PROC;\n  DCL S CHAR(5) INIT('a b'); /* c */\n  X = 1E2 + LENGTH(S);\nEND L;\n";
+        let theme = AnsiTheme::default();
+        assert_eq!(strip(&highlight_ansi(code, &theme)), code);
+        assert_eq!(highlight_ansi(code, &AnsiTheme::plain()), code);
+        
+        let mut theme = AnsiTheme::plain();
+        theme.set(TokenType::Keyword, Some("1;31"));
+        assert_eq!(highlight_ansi("DCL X;", &theme), "\x1b[1;31mDCL\x1b[0m X;");
+        // Whitespace is never wrapped, even if the theme names it
+        theme.set(TokenType::Whitespace, Some("7"));
+        assert_eq!(highlight_ansi("DCL X;", &theme), "\x1b[1;31mDCL\x1b[0m X;");
+        theme.set(TokenType::Keyword, None);
+        assert_eq!(theme.color(TokenType::Keyword), None);
+        assert_eq!(AnsiTheme::default().color(TokenType::KeywordControl), Some("1;34"));
+    }
+    
+    #[test]
+    fn test_tokenize_json() {
+        let code = "L: PROC;\r\n  S = 'Say \"hi\"\\ ''é''';\t/* tab\n ü */ dcl x;\n";
+        // The original implementation, serializing the owned tokens
+        let expected = serde_json::to_string(&tokenize(code)).unwrap();
+        let json = tokenize_json(code);
+        assert_eq!(json, expected);
+        assert!(json.contains("\"text\":\"dcl\",\"type\":\"keyword\",\"start\":50,\"end\":53,\"line\":2,\"column\":6,\"canonical\":\"DCL\""));
+        
+        let parsed: Vec<Token> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.len(), tokenize(code).len());
+        assert_eq!(parsed[0].token_type, TokenType::Label);
+        assert_eq!(tokenize_json(""), "[]");
+    }
+    
+    #[test]
+    fn test_strip_comments() {
+        let code = "X = 1; /* é\r\n ; */ Y = '/* kept */';\n/**/Z";
+        assert_eq!(strip_comments(code, false), "X = 1;  Y = '/* kept */';\nZ");
+        
+        let blanked = strip_comments(code, true);
+        assert_eq!(blanked, "X = 1;      \r\n      Y = '/* kept */';\n    Z");
+        assert_eq!(blanked.len(), code.len());
+        assert_eq!(blanked.find('Y'), code.find('Y'));
+        assert_eq!(blanked.lines().count(), code.lines().count());
+    }
+    
+    #[test]
+    fn test_highlighter() {
+        let highlighter = Highlighter::new(
+            vec!["MYMACRO".to_string()],
+            &FormatOptions::default(),
+            &LexOptions { line_comments: true, ..LexOptions::default() },
+        );
+        let code = "mymacro(X); -- note\nL: Y = MyMacro;";
+        let first = highlighter.tokenize_flat(code);
+        assert_eq!(highlighter.tokenize_flat(code), first);
+        
+        let tokens = highlighter.tokenize(code);
+        let types: Vec<(&str, TokenType)> = tokens
+            .iter()
+            .filter(|t| !matches!(t.token_type, TokenType::Whitespace | TokenType::Newline))
+            .map(|t| (t.text.as_str(), t.token_type))
+            .collect();
+        assert_eq!(types[..6], [
+            ("mymacro", TokenType::Keyword),
+            ("(", TokenType::Punctuation),
+            ("X", TokenType::Identifier),
+            (")", TokenType::Punctuation),
+            (";", TokenType::Punctuation),
+            ("-- note", TokenType::Comment),
+        ]);
+        assert_eq!(types[6], ("L", TokenType::Label));
+        assert_eq!(types[10], ("MyMacro", TokenType::Keyword));
+        assert_eq!(highlighter.stats(code).count(TokenType::Keyword), 2);
+        
+        // The range covers the second line only, at its real offsets
+        let second_line = code.find('L').unwrap();
+        let range = highlighter.tokenize_range(code, second_line + 1, second_line + 1);
+        assert_eq!(range, first[first.len() - range.len()..]);
+        assert_eq!(range[1] as usize, second_line);
+        
+        // Card layout: sequence columns are carved out as in `tokenize_with_options`
+        let card = Highlighter::new(Vec::new(), &FormatOptions::card(), &LexOptions::default());
+        let code = format!(" X = 1;{}00010000", " ".repeat(65));
+        let spans = |tokens: Vec<Token>| -> Vec<(TokenType, usize, usize)> {
+            tokens.iter().map(|t| (t.token_type, t.start, t.end)).collect()
+        };
+        assert_eq!(spans(card.tokenize(&code)), spans(tokenize_with_options(&code, &FormatOptions::card())));
+        assert!(card.tokenize(&code).iter().any(|t| t.token_type == TokenType::SequenceNumber));
+    }
+    
+    #[test]
+    fn test_at_sign_identifiers() {
+        assert_eq!(significant_types("@PTR = ADDR(X);"), [
+            ("@PTR".into(), TokenType::Identifier),
+            ("=".into(), TokenType::Operator),
+            ("ADDR".into(), TokenType::Builtin),
+            ("(".into(), TokenType::Punctuation),
+            ("X".into(), TokenType::Identifier),
+            (")".into(), TokenType::Punctuation),
+            (";".into(), TokenType::Punctuation),
+        ]);
+        // `@` never becomes an operator: alone or leading a builtin's name it is
+        // still part of an identifier
+        assert_eq!(significant_types("P = ADDRESS(@Q) + @;")[2..6], [
+            ("ADDRESS".into(), TokenType::Builtin),
+            ("(".into(), TokenType::Punctuation),
+            ("@Q".into(), TokenType::Identifier),
+            (")".into(), TokenType::Punctuation),
+        ]);
+        assert_eq!(significant_types("P = ADDRESS(@Q) + @;")[7], ("@".into(), TokenType::Identifier));
+        assert_eq!(significant_types("@ADDR(X)")[0], ("@ADDR".into(), TokenType::Identifier));
+        
+        let addr = tokenize_semantic("@PTR = ADDR(X);").into_iter().find(|t| t.text == "ADDR").unwrap();
+        assert_eq!(addr.modifiers & MODIFIER_BUILTIN_CALL, MODIFIER_BUILTIN_CALL);
+    }
+    
+    #[test]
+    fn test_highlighter_tokenize_into() {
+        let mut highlighter = Highlighter::new(Vec::new(), &FormatOptions::default(), &LexOptions::default());
+        let long = "DCL X FIXED; X = X + 1;\n".repeat(50);
+        
+        let written = highlighter.tokenize_into(&long);
+        assert_eq!(written, highlighter.buffer_len());
+        assert_eq!(highlighter.buffer, highlighter.tokenize_flat(&long));
+        let capacity = highlighter.buffer.capacity();
+        let ptr = highlighter.buffer_ptr();
+        
+        // A shorter document reuses the same allocation
+        let short = "L: Y = 2;";
+        assert_eq!(highlighter.tokenize_into(short), highlighter.tokenize_flat(short).len());
+        assert_eq!(highlighter.buffer, highlighter.tokenize_flat(short));
+        assert_eq!(highlighter.buffer.capacity(), capacity);
+        assert_eq!(highlighter.buffer_ptr(), ptr);
+        assert_eq!(highlighter.buffer[0], TokenType::Label as u32);
+        
+        assert_eq!(highlighter.tokenize_into(&long), written);
+        assert_eq!(highlighter.buffer.capacity(), capacity);
+    }
+    
+    #[test]
+    fn test_national_characters_option() {
+        let lex = |code: &str, options: LexOptions| -> Vec<(String, TokenType)> {
+            tokenize_with_lex_options(code, &options)
+                .into_iter()
+                .filter(|t| t.token_type != TokenType::Whitespace)
+                .map(|t| (t.text, t.token_type))
+                .collect()
+        };
+        let standard = LexOptions::default();
+        assert_eq!(lex("A#B = 1;", standard)[0], ("A#B".to_string(), TokenType::Identifier));
+        
+        let no_hash = LexOptions { allow_hash: false, ..LexOptions::default() };
+        assert_eq!(lex("A#B = 1;", no_hash)[..4], [
+            ("A".to_string(), TokenType::Identifier),
+            ("#".to_string(), TokenType::Unknown),
+            ("B".to_string(), TokenType::Identifier),
+            ("=".to_string(), TokenType::Operator),
+        ]);
+        assert_eq!(lex("#IF", no_hash), [("#".to_string(), TokenType::Unknown), ("IF".to_string(), TokenType::KeywordControl)]);
+        assert_eq!(lex("X#1B", no_hash)[2], ("1B".to_string(), TokenType::Number));
+        
+        // Only the excluded characters are affected; positions stay exact
+        let no_at_dollar = LexOptions { allow_at: false, allow_dollar: false, ..LexOptions::default() };
+        let tokens = tokenize_with_lex_options("\n  P@Q $R S#T", &no_at_dollar);
+        let s_t = tokens.iter().find(|t| t.text == "S#T").unwrap();
+        assert_eq!((s_t.start, s_t.line, s_t.column), (10, 1, 9));
+        assert!(tokens.iter().any(|t| t.text == "$" && t.token_type == TokenType::Unknown));
+    }
+    
+    #[test]
+    fn test_tokenize_folded() {
+        let code = "main: Proc; myvar = Substr('literal', 1) /* Note */ + 1e2; dcl Größe;";
+        let texts = |case_fold: CaseFold| -> String {
+            tokenize_folded(code, case_fold).into_iter().map(|t| t.text).collect()
+        };
+        assert_eq!(texts(CaseFold::Upper), "MAIN: PROC; MYVAR = SUBSTR('literal', 1) /* Note */ + 1e2; DCL GRößE;");
+        assert_eq!(texts(CaseFold::Lower), "main: proc; myvar = substr('literal', 1) /* Note */ + 1e2; dcl größe;");
+        assert_eq!(texts(CaseFold::None), code);
+        
+        // Positions are those of the original source
+        for (folded, original) in tokenize_folded(code, CaseFold::Upper).iter().zip(tokenize(code)) {
+            assert_eq!((folded.start, folded.end, folded.text.len()), (original.start, original.end, original.text.len()));
+        }
+    }
+    
+    #[test]
+    fn test_tokenize_collapsed() {
+        let code = "   \n  X = 1;";
+        
+        let folded = tokenize_collapsed(code, true);
+        assert_eq!(folded[0].token_type, TokenType::Whitespace);
+        assert_eq!((folded[0].start, folded[0].end), (0, 6));
+        assert_eq!(folded[0].text, "   \n  ");
+        assert_eq!(folded[1].text, "X");
+        
+        // Without folding the newline still separates the runs
+        let kept = tokenize_collapsed(code, false);
+        let types: Vec<TokenType> = kept.iter().take(3).map(|t| t.token_type).collect();
+        assert_eq!(types, [TokenType::Whitespace, TokenType::Newline, TokenType::Whitespace]);
+        
+        let runs = tokenize_collapsed("A\n\n \nB", true);
+        assert_eq!(runs.len(), 3);
+        assert_eq!((runs[1].start, runs[1].end, runs[1].line), (1, 5, 0));
+    }
+    
+    #[test]
+    fn test_comments() {
+        let types: Vec<(&str, TokenType)> = tokenize("X /* plain */ Y")
+            .iter()
+            .map(|t| (&"X /* plain */ Y"[t.start..t.end], t.token_type))
+            .filter(|(_, ty)| *ty != TokenType::Whitespace)
+            .collect();
+        assert_eq!(types, [("X", TokenType::Identifier), ("/* plain */", TokenType::Comment), ("Y", TokenType::Identifier)]);
+        
+        for comment in ["/**/", "/* a * b / c ** / */", "/*** boxed ***/", "/* 2 */"] {
+            let tokens = tokenize(&format!("{}X", comment));
+            assert_eq!((tokens[0].text.as_str(), tokens[0].token_type), (comment, TokenType::Comment));
+            assert_eq!(tokens[1].text, "X");
+        }
+        
+        // Unterminated at end of input: one Comment to the end
+        for code in ["A = 1; /* open\nB = 2; /", "/*/"] {
+            let start = code.find("/*").unwrap();
+            let tokens: Vec<Token> = tokenize(code).into_iter().filter(|t| t.start >= start).collect();
+            assert_eq!(tokens.len(), 1, "{}", code);
+            assert_eq!((tokens[0].token_type, tokens[0].end), (TokenType::Comment, code.len()));
+        }
+    }
+    
+    #[test]
+    fn test_diagnostics_unterminated_string() {
+        let code = "X = 1;\nY = 'unterminated;";
+        let diags = diagnostics(code);
+        
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].start, code.find('\'').unwrap());
+        assert_eq!(diags[0].end, code.len());
+        assert_eq!(diags[0].severity, Severity::Error);
+        assert!(diags[0].message.contains("string"));
+    }
+    
+    #[test]
+    fn test_diagnostics_unterminated_comment() {
+        let code = "X = 1; /* never closed\nY = 2;";
+        let diags = diagnostics(code);
+        
+        assert_eq!(diags.len(), 1);
+        assert_eq!((diags[0].start, diags[0].end), (7, code.len()));
+        assert!(diags[0].message.contains("comment"));
+    }
+    
+    #[test]
+    fn test_diagnostics_clean_file() {
+        let code = "MAIN: PROC; /* ok */ S = 'IT''S'; X = A / B * C; END;";
+        assert!(diagnostics(code).is_empty());
+    }
+    
+    fn apply_edit(old_code: &str, edit: &TokenEdit) -> Vec<(TokenType, String, usize)> {
+        let old = tokenize(old_code);
+        old[..edit.first]
+            .iter()
+            .chain(&edit.tokens)
+            .map(|t| (t.token_type, t.text.clone(), t.start))
+            .chain(old[edit.last..].iter().map(|t| (t.token_type, t.text.clone(), (t.start as isize + edit.byte_delta) as usize)))
+            .collect()
+    }
+    
+    fn flatten(code: &str) -> Vec<(TokenType, String, usize)> {
+        tokenize(code).into_iter().map(|t| (t.token_type, t.text, t.start)).collect()
+    }
+    
+    #[test]
+    fn test_retokenize_single_line() {
+        let old_code = "X = 1;\nY = 2;\nZ = 3;";
+        let new_code = "X = 1;\nY = 42 + A;\nZ = 3;";
+        let edit = retokenize(old_code, new_code, 11, 1, 6);
+        
+        assert_eq!(edit.byte_delta, 5);
+        assert_eq!(edit.line_delta, 0);
+        assert!(edit.tokens.iter().all(|t| t.line == 1));
+        assert!(edit.first >= 4);
+        assert_eq!(apply_edit(old_code, &edit), flatten(new_code));
+    }
+    
+    #[test]
+    fn test_retokenize_opening_comment_ripples() {
+        let old_code = "X = 1;\nY = 2;\nZ = 3; */\nW = 4;";
+        let new_code = "X = 1;\n/* Y = 2;\nZ = 3; */\nW = 4;";
+        let edit = retokenize(old_code, new_code, 7, 0, 3);
+        
+        // The new comment swallows the next line too
+        let comment = edit.tokens.iter().find(|t| t.token_type == TokenType::Comment).unwrap();
+        assert_eq!(comment.text, "/* Y = 2;\nZ = 3; */");
+        assert!(edit.tokens.iter().all(|t| t.token_type != TokenType::Identifier || t.text != "Z"));
+        assert_eq!(apply_edit(old_code, &edit), flatten(new_code));
+    }
+    
+    #[test]
+    fn test_retokenize_reclassifies_earlier_lines() {
+        // The `=` on the next line makes SUBSTR a pseudovariable
+        let old_code = "SUBSTR(S, 1, 2)\n  'AB';";
+        let new_code = "SUBSTR(S, 1, 2)\n  = 'AB';";
+        let edit = retokenize(old_code, new_code, 18, 0, 2);
+        assert_eq!(edit.first, 0);
+        assert_eq!(edit.tokens[0].token_type, TokenType::Pseudovariable);
+        assert_eq!(apply_edit(old_code, &edit), flatten(new_code));
+        
+        // A colon on the next line makes the name before it a label
+        let old_code = "\rSUBSTR\n'";
+        let new_code = "\rSUBSTR\n:";
+        let edit = retokenize(old_code, new_code, 8, 1, 1);
+        assert!(edit.tokens.iter().any(|t| t.text == "SUBSTR" && t.token_type == TokenType::Label));
+        assert_eq!(apply_edit(old_code, &edit), flatten(new_code));
+    }
+    
+    #[test]
+    fn test_retokenize_restarts_after_earlier_statements() {
+        // Only the statement around the edit is lexed again
+        let old_code = "A: PROC;\n  DCL M(*) FIXED;\n  X = 1;";
+        let new_code = "A: PROC;\n  DCL M(*) FIXED;\n  Y,X = 1;";
+        let positions = |tokens: &[Token]| -> Vec<(usize, usize, usize)> { tokens.iter().map(|t| (t.start, t.line, t.column)).collect() };
+        let edit = retokenize(old_code, new_code, 28, 0, 2);
+        let restart = tokenize(old_code).iter().rposition(|t| t.text == ";" && t.start < 27).unwrap() + 1;
+        assert!(edit.first >= restart);
+        assert_eq!(positions(&edit.tokens), positions(&tokenize(new_code)[edit.first..edit.first + edit.tokens.len()]));
+        
+        // Parentheses left open across a `;` carry into later statements, so
+        // the scan falls back to an earlier restart point
+        let old_code = "A(1;\nX = 1;";
+        let new_code = "A(1;\nX = 1:2;";
+        let edit = retokenize(old_code, new_code, 10, 0, 2);
+        assert_eq!(edit.tokens[0].token_type, TokenType::Operator);
+        assert_eq!(apply_edit(old_code, &edit), flatten(new_code));
+        
+        // Any single insertion matches lexing the new code from scratch,
+        // positions included
+        let old_code = "L: DO I = 1 TO N;\r\n\tSUBSTR(S, I, 1)\r\n  = 'A'; /* c\r\n */ PUT EDIT(X) (A(3));\r\nEND L;";
+        for insert in ["=", ";", "(", ")", "/*", "'", ":", "X\n"] {
+            for at in (0..=old_code.len()).filter(|&at| old_code.is_char_boundary(at)) {
+                let new_code = format!("{}{}{}", &old_code[..at], insert, &old_code[at..]);
+                let edit = retokenize(old_code, &new_code, at, 0, insert.len());
+                let expected = tokenize(&new_code);
+                assert_eq!(apply_edit(old_code, &edit), flatten(&new_code), "{:?} at {}", insert, at);
+                let expected = positions(&expected[edit.first..edit.first + edit.tokens.len()]);
+                assert_eq!(positions(&edit.tokens), expected, "{:?} at {}", insert, at);
+            }
+        }
+    }
+    
+    #[test]
+    fn test_retokenize_no_op() {
+        let code = "A: PROC;\n  X = 1;\nEND;";
+        let edit = retokenize(code, code, 12, 0, 0);
+        
+        assert_eq!(edit.first, edit.last);
+        assert!(edit.tokens.is_empty());
+        assert_eq!((edit.byte_delta, edit.line_delta), (0, 0));
+    }
+    
+    #[test]
+    fn test_tokenize_range_snaps_offsets() {
+        let code = "X = 'é';\nY = 1;";
+        assert_eq!(snap_to_char_boundary(code, 6), 5);
+        assert_eq!(snap_to_char_boundary(code, 7), 7);
+        assert_eq!(snap_to_char_boundary(code, 100), code.len());
+        
+        // Offsets inside the two-byte 'é' lex its whole line instead of panicking
+        let first_line = tokenize_range(code, 0, 0);
+        assert_eq!(tokenize_range(code, 6, 6), first_line);
+        assert_eq!(first_line.chunks(3).last().unwrap(), &[TokenType::Newline as u32, 9, 10]);
+        assert_eq!(tokenize_range(code, 6, 100), tokenize_flat(code));
+        assert_eq!(tokenize_range_safe(code, 6, 6).tokens(), first_line);
+    }
+    
+    #[test]
+    fn test_tokenize_range_safe_plain_edit() {
+        let code = "A = 1;\nB = 2;\nC = 3;";
+        let range = tokenize_range_safe(code, 9, 10);
+        
+        assert_eq!((range.start(), range.end()), (7, 14));
+        assert!(!range.tail_invalidated());
+        assert_eq!(range.tokens(), tokenize_range(code, 9, 10));
+    }
+    
+    #[test]
+    fn test_tokenize_range_safe_opened_comment() {
+        // `/*` was just typed on line 1; the comment now runs through line 3
+        let code = "A = 1;\n/* B = 2;\nC = 3;\nD = 4; */\nE = 5;";
+        let range = tokenize_range_safe(code, 7, 9);
+        
+        assert!(range.tail_invalidated());
+        assert_eq!(range.start(), 7);
+        assert_eq!(range.end(), code.find("E").unwrap());
+        assert_eq!(range.tokens()[0], TokenType::Comment as u32);
+    }
+    
+    #[test]
+    fn test_tokenize_range_safe_closed_comment() {
+        // `*/` was just typed on line 1, closing a comment opened on line 0
+        let code = "/* A = 1;\nB = 2; */ C = 3;\nD = 4; */";
+        let edit = code.find("*/").unwrap();
+        let range = tokenize_range_safe(code, edit, edit + 2);
+        
+        assert!(range.tail_invalidated());
+        assert_eq!(range.start(), 0);
+        let flat = range.tokens();
+        assert_eq!((flat[0], flat[2] as usize), (TokenType::Comment as u32, edit + 2));
+        assert!(flat.chunks(3).any(|t| t[0] == TokenType::Identifier as u32));
+    }
+    
+    #[test]
+    fn test_semantic_tokens_lsp() {
+        let code = "DCL X;\nY = 'é'; /* a\nb */     IF";
+        let data = semantic_tokens_lsp(code);
+        let entries: Vec<&[u32]> = data.chunks(5).collect();
+        
+        assert_eq!(entries, [
+            &[0, 0, 3, 0, 0][..], // DCL
+            &[0, 4, 1, 8, 0],     // X
+            &[1, 0, 1, 8, 0],     // Y
+            &[0, 2, 1, 5, 0],     // =
+            &[0, 2, 3, 2, 0],     // 'é' - one UTF-16 unit for é
+            &[0, 5, 4, 3, 0],     // "/* a"
+            &[1, 0, 4, 3, 0],     // "b */"
+            &[0, 9, 2, 0, 0],     // IF at line 2, column 9
+        ]);
+        
+        let data = semantic_tokens_lsp("X = 1;\n     IF");
+        assert_eq!(&data[data.len() - 5..], &[1, 5, 2, 0, 0]);
+        assert_eq!(SEMANTIC_TOKEN_LEGEND[data[data.len() - 2] as usize], "keyword");
+    }
+    
+    #[test]
+    fn test_process_directive() {
+        let code = "*PROCESS OPT(2) LANGLVL(SAA); /* options */\n *process;\nMAIN: PROC;";
+        let tokens = tokenize(code);
+        
+        assert_eq!(tokens[0].token_type, TokenType::Directive);
+        assert_eq!(tokens[0].text, "*PROCESS OPT(2) LANGLVL(SAA);");
+        assert_eq!(tokens[2].token_type, TokenType::Comment);
+        let second = tokens.iter().find(|t| t.line == 1 && t.token_type == TokenType::Directive).unwrap();
+        assert_eq!(second.text, "*process;");
+        
+        // Multiplication is not a process card
+        let tokens = significant("* 2");
+        assert_eq!((tokens[0].token_type, tokens[1].token_type), (TokenType::Operator, TokenType::Number));
+        let tokens = significant("X = A *PROCESSED;");
+        assert_eq!(tokens[3].text, "*");
+        assert_eq!(tokens[3].token_type, TokenType::Operator);
+        assert_eq!(tokens[4].text, "PROCESSED");
+    }
+    
+    #[test]
+    fn test_canonical_keyword_spelling() {
+        let tokens = significant("dcl s Char(5); x = Substr(s, 1);");
+        
+        assert_eq!(tokens[0].text, "dcl");
+        assert_eq!(tokens[0].canonical.as_deref(), Some("DCL"));
+        assert_eq!(tokens[2].canonical.as_deref(), Some("CHAR"));
+        assert_eq!(tokens[1].canonical, None);
+        let substr = tokens.iter().find(|t| t.text == "Substr").unwrap();
+        assert_eq!(substr.canonical.as_deref(), Some("SUBSTR"));
+        assert!(tokens.iter().filter(|t| t.token_type == TokenType::Identifier).all(|t| t.canonical.is_none()));
+    }
+    
+    #[test]
+    fn test_classify_word() {
+        assert_eq!(classify_word("substr"), TokenType::Builtin);
+        assert_eq!(classify_word("procedure"), TokenType::KeywordControl);
+        assert!(classify_word("Dcl").is_keyword());
+        assert_eq!(classify_word("myvar"), TokenType::Identifier);
+        assert_eq!(classify_word("%include"), TokenType::Preprocessor);
+        assert_eq!(classify_word("DCLX"), TokenType::Identifier);
+        assert_eq!(classify_word("DCL X"), TokenType::Identifier);
+        assert_eq!(classify_word("42"), TokenType::Identifier);
+        assert_eq!(classify_word(""), TokenType::Identifier);
+        
+        // The public word tables are exactly what the lexer recognizes
+        for word in CONTROL_KEYWORDS.iter().chain(IO_KEYWORDS).chain(STRING_BUILTINS) {
+            assert_ne!(classify_word(word), TokenType::Identifier, "{}", word);
+            assert_eq!(classify_word(&word.to_lowercase()), classify_word(word));
+        }
+    }
+    
+    #[test]
+    fn test_builtin_categories() {
+        assert_eq!(builtin_category("SIN"), Some(BuiltinCategory::Math));
+        assert_eq!(builtin_category("index"), Some(BuiltinCategory::String));
+        assert_eq!(builtin_category("HBOUND"), Some(BuiltinCategory::Array));
+        assert_eq!(builtin_category("DateTime"), Some(BuiltinCategory::DateTime));
+        assert_eq!(builtin_category("ADDR"), Some(BuiltinCategory::Storage));
+        assert_eq!(builtin_category("ONCODE"), Some(BuiltinCategory::Condition));
+        assert_eq!(builtin_category("DCL"), None);
+        
+        // Every builtin the lexer knows has exactly one category
+        for (_, names) in BUILTIN_CATEGORIES {
+            for name in names {
+                assert_eq!(classify_word(name), TokenType::Builtin, "{}", name);
+                let count = BUILTIN_CATEGORIES.iter().filter(|(_, n)| n.contains(name)).count();
+                assert_eq!(count, 1, "{}", name);
+            }
+        }
+    }
+    
+    #[test]
+    fn test_locator_operators() {
+        let tokens = tokenize("PTR->FIELD = H=>X;");
+        let texts: Vec<(&str, TokenType)> = tokens.iter().map(|t| (t.text.as_str(), t.token_type)).collect();
+        assert_eq!(texts[..3], [("PTR", TokenType::KeywordType), ("->", TokenType::Operator), ("FIELD", TokenType::Identifier)]);
+        assert!(texts.contains(&("=>", TokenType::Operator)));
+        
+        let tokens = tokenize("P->Q");
+        assert_eq!(tokens.iter().map(|t| t.token_type).collect::<Vec<_>>(), [TokenType::Identifier, TokenType::Operator, TokenType::Identifier]);
+        
+        // Subtraction and a spaced-out `- >` stay separate
+        let tokens = significant("A-B; A - >B;");
+        let ops: Vec<&str> = tokens.iter().filter(|t| t.token_type == TokenType::Operator).map(|t| t.text.as_str()).collect();
+        assert_eq!(ops, ["-", "-", ">"]);
+    }
+    
+    #[test]
+    fn test_loc() {
+        let code = "/* header */\n\
+                    \n\
+                    P: PROC;\n   \n\
+                    /* a block\n   comment */\n\
+                    DCL X FIXED; /* trailing */\n\
+                    S = 'two\n  lines';\n\
+                    END P;";
+        assert_eq!(loc(code), LocMetrics { total_lines: 10, blank_lines: 2, comment_lines: 3, code_lines: 5 });
+        
+        assert_eq!(loc(""), LocMetrics::default());
+        assert_eq!(loc("X;\r\n\r\n"), LocMetrics { total_lines: 2, blank_lines: 1, comment_lines: 0, code_lines: 1 });
+        // The end of a block comment shares its line with code
+        assert_eq!(loc("/* a\n */ X;\n").code_lines, 1);
+        assert_eq!(loc("/* a\n */ X;\n").comment_lines, 1);
+    }
+    
+    #[test]
+    fn test_token_stats() {
+        let code = "DCL X FIXED BIN(31) STATIC;\nDCL S CHAR(8); /* name */\n";
+        let stats = token_stats(code);
+        
+        assert_eq!(stats.count(TokenType::Keyword), 2);
+        assert_eq!(stats.count(TokenType::KeywordType), 2);
+        assert_eq!(stats.count(TokenType::KeywordStorage), 1);
+        assert_eq!(stats.count(TokenType::Comment), 1);
+        assert_eq!(stats.count(TokenType::Newline), 2);
+        assert_eq!(stats.count(TokenType::Label), 0);
+        assert_eq!(stats.histogram.values().sum::<usize>(), stats.total_tokens);
+        assert_eq!(stats.total_tokens, tokenize(code).len());
+        assert_eq!(stats.total_bytes, code.len());
+        
+        let json = serde_json::to_string(&stats).unwrap();
+        assert!(json.contains("\"whitespace\":"));
+    }
+    
+    fn types_of<'a>(tokens: &'a [Token], texts: &[&str]) -> Vec<(&'a str, TokenType)> {
+        texts
+            .iter()
+            .map(|text| {
+                let t = tokens.iter().find(|t| t.text == *text).unwrap();
+                (t.text.as_str(), t.token_type)
+            })
+            .collect()
+    }
+    
+    #[test]
+    fn test_condition_names() {
+        let tokens = tokenize("ON ERROR BEGIN; END;");
+        assert_eq!(types_of(&tokens, &["ON", "ERROR"]), [("ON", TokenType::Keyword), ("ERROR", TokenType::Condition)]);
+        
+        let tokens = tokenize("SIGNAL CONDITION(FOO);");
+        assert_eq!(types_of(&tokens, &["CONDITION", "FOO"]), [("CONDITION", TokenType::Condition), ("FOO", TokenType::Condition)]);
+        
+        let tokens = tokenize("ON ENDFILE(SYSIN) EOF = '1'B; REVERT ZERODIVIDE;");
+        assert_eq!(
+            types_of(&tokens, &["ENDFILE", "SYSIN", "ZERODIVIDE"]),
+            [("ENDFILE", TokenType::Condition), ("SYSIN", TokenType::Builtin), ("ZERODIVIDE", TokenType::Condition)]
+        );
+        
+        // Outside ON/SIGNAL/REVERT the words keep their usual types
+        let tokens = tokenize("DCL ERROR_COUNT FIXED; X = SIZE;");
+        assert!(tokens.iter().all(|t| t.token_type != TokenType::Condition));
+        
+        // `tokenize_flat`, which the editor highlights from, agrees
+        assert_eq!(flat_type_of("ON ERROR X = 1;", "ERROR"), Some(TokenType::Condition));
+    }
+    
+    #[test]
+    fn test_tokenize_flat_eof() {
+        let code = "X = 'é';";
+        let flat = tokenize_flat_eof(code, true);
+        assert_eq!(flat[flat.len() - 3..], [TokenType::Eof as u32, code.len() as u32, code.len() as u32]);
+        assert_eq!(flat[..flat.len() - 3], tokenize_flat(code)[..]);
+        assert_eq!(tokenize_flat_eof(code, false), tokenize_flat(code));
+        assert_eq!(tokenize_flat_eof("", true), [TokenType::Eof as u32, 0, 0]);
+    }
+    
+    #[test]
+    fn test_token_text() {
+        let code = "/* Größe ♦ */ X = 'né';";
+        let tokens = tokenize(code);
+        for token in &tokens {
+            assert_eq!(slice_token(code, token), token.text);
+        }
+        
+        let flat = tokenize_flat(code);
+        let comment = token_text_at(code, flat[1] as usize, flat[2] as usize);
+        assert_eq!(comment, "/* Größe ♦ */");
+        assert_eq!(token_text_at(code, 5, 6), "", "inside the two-byte ö");
+        assert_eq!(token_text_at(code, 0, code.len() + 1), "");
+    }
+    
+    #[test]
+    fn test_tokenize_packed() {
+        let code = "main: PROC; /* hi */\n  PUT LIST('née', X->Y);\nEND;";
+        let packed = tokenize_packed(code);
+        assert_eq!(packed.len() % PACKED_RECORD_SIZE, 0);
+        
+        let decoded: Vec<(u8, usize, usize)> = packed
+            .chunks_exact(PACKED_RECORD_SIZE)
+            .map(|r| {
+                let start = u32::from_le_bytes(r[1..5].try_into().unwrap());
+                let end = u32::from_le_bytes(r[5..9].try_into().unwrap());
+                (r[0], start as usize, end as usize)
+            })
+            .collect();
+        let expected: Vec<(u8, usize, usize)> = tokenize(code)
+            .iter()
+            .map(|t| (t.token_type as u8, t.start, t.end))
+            .collect();
+        assert_eq!(decoded, expected);
+    }
+    
+    fn chunked(code: &str, byte_limit: usize) -> Vec<u32> {
+        let mut state = LexerState::new();
+        let mut tokens = Vec::new();
+        while state.offset() < code.len() {
+            let (chunk, next) = tokenize_chunk(code, state, byte_limit);
+            tokens.extend(chunk);
+            state = next;
+        }
+        tokens
+    }
+    
+    #[test]
+    fn test_tokenize_chunk() {
+        let code = "X = 1; /* a comment\n   spanning *** lines */ Y = 'it''s';\n*PROCESS OPT(2);\nZ = '01'B; /* open";
+        let expected = tokenize_flat(code);
+        
+        // Every limit, including ones that cut the comment in the middle
+        for byte_limit in 0..=code.len() {
+            assert_eq!(chunked(code, byte_limit), expected, "byte_limit {}", byte_limit);
+        }
+        
+        let (first, state) = tokenize_chunk(code, LexerState::new(), 12);
+        assert!(state.in_comment());
+        assert_eq!(first, tokenize_flat("X = 1; ")[..], "only tokens before the comment are emitted");
+        let (rest, state) = tokenize_chunk(code, state, code.len());
+        assert_eq!(state.offset(), code.len());
+        assert_eq!([first, rest].concat(), expected);
+    }
+    
+    #[test]
+    fn test_includes() {
+        assert_eq!(
+            includes("%INCLUDE PAYROLL;"),
+            [IncludeRef { ddname: None, member: "PAYROLL".into(), start: 9, end: 16 }]
+        );
+        assert_eq!(
+            includes("  %INCLUDE SYSLIB(TAXCALC);"),
+            [IncludeRef { ddname: Some("SYSLIB".into()), member: "TAXCALC".into(), start: 11, end: 26 }]
+        );
+        
+        let code = "%include a, lib ( b );\nX = INCLUDE;";
+        let found: Vec<(Option<String>, String)> = includes(code).into_iter().map(|r| (r.ddname, r.member)).collect();
+        assert_eq!(found, [(None, "a".into()), (Some("lib".into()), "b".into())]);
+    }
+    
+    #[test]
+    fn test_tokenize_flat_utf16() {
+        let code = "/* é ♦ 𝄞 */ X = 1;";
+        let bytes = tokenize_flat(code);
+        let units = tokenize_flat_utf16(code);
+        assert_eq!(bytes.len(), units.len());
+        
+        // The comment is 17 bytes but 12 UTF-16 units: é (2 → 1), ♦ (3 → 1), 𝄞 (4 → 2)
+        assert_eq!(units[..3], [TokenType::Comment as u32, 0, 12]);
+        let x = 2 * 3;
+        assert_eq!(&code[bytes[x + 1] as usize..bytes[x + 2] as usize], "X");
+        assert_eq!((bytes[x + 1], units[x + 1]), (18, 13));
+        
+        let utf16: Vec<u16> = code.encode_utf16().collect();
+        assert_eq!(String::from_utf16(&utf16[units[x + 1] as usize..units[x + 2] as usize]).unwrap(), "X");
+    }
+    
+    #[test]
+    fn test_preprocessor_statement_names() {
+        assert_eq!(significant_types("%DCL N FIXED; N = 1;"), [
+            ("%DCL".into(), TokenType::Preprocessor),
+            ("N".into(), TokenType::Preprocessor),
+            ("FIXED".into(), TokenType::KeywordType),
+            (";".into(), TokenType::Punctuation),
+            ("N".into(), TokenType::Identifier),
+            ("=".into(), TokenType::Operator),
+            ("1".into(), TokenType::Number),
+            (";".into(), TokenType::Punctuation),
+        ]);
+        assert_eq!(significant_types("%PROC FOO RETURNS(CHAR);"), [
+            ("%PROC".into(), TokenType::Preprocessor),
+            ("FOO".into(), TokenType::Preprocessor),
+            ("RETURNS".into(), TokenType::Keyword),
+            ("(".into(), TokenType::Punctuation),
+            ("CHAR".into(), TokenType::KeywordType),
+            (")".into(), TokenType::Punctuation),
+            (";".into(), TokenType::Punctuation),
+        ]);
+        
+        let names = |code: &str| -> Vec<TokenType> {
+            significant_types(code).into_iter().filter(|(t, _)| t.len() == 1 && t.chars().all(|c| c.is_ascii_alphabetic())).map(|(_, t)| t).collect()
+        };
+        // Assignments and %IF conditions; a %name inside ordinary code is only a reference
+        assert_eq!(names("%N = N + 1; %IF N > 2 %THEN %GOTO L; X = %N + Y;"), [
+            TokenType::Preprocessor, TokenType::Preprocessor, TokenType::Preprocessor,
+            TokenType::Identifier, TokenType::Identifier,
+        ]);
+        // %INCLUDE members stay names, so `includes` still finds them
+        assert_eq!(names("%INCLUDE S(M);"), [TokenType::Identifier, TokenType::Identifier]);
+        assert_eq!(includes("%INCLUDE S(M);").len(), 1);
+        assert_eq!(classify_word("%PROCEDURE"), TokenType::Preprocessor);
+        assert_eq!(classify_word("%return"), TokenType::Preprocessor);
+    }
+    
+    #[test]
+    fn test_preprocessor_variables() {
+        assert_eq!(significant_types("%MYVAR"), [("%MYVAR".to_string(), TokenType::Preprocessor)]);
+        assert_eq!(significant_types("X = %COUNT + 1;")[2], ("%COUNT".to_string(), TokenType::Preprocessor));
+        
+        // Statement keywords still lex as themselves, and longer names are not split
+        assert_eq!(significant_types("%IF %IFX %END;")[..3], [
+            ("%IF".to_string(), TokenType::Preprocessor),
+            ("%IFX".to_string(), TokenType::Preprocessor),
+            ("%END".to_string(), TokenType::Preprocessor),
+        ]);
+        assert_eq!(significant_types("% 1")[0], ("%".to_string(), TokenType::Unknown));
+    }
+    
+    #[test]
+    fn test_token_type_names() {
+        for (code, &token_type) in TokenType::ALL.iter().enumerate() {
+            assert_eq!(token_type as usize, code);
+            let name = token_type_name(code as u32);
+            assert_eq!(token_type_code(&name), code as i32, "{}", name);
+            assert_eq!(serde_json::to_string(&token_type).unwrap(), format!("\"{}\"", name));
+            assert_eq!(token_type.name(), format!("{:?}", token_type).to_ascii_lowercase());
+        }
+        assert_eq!(TokenType::NAMES.len(), TokenType::ALL.len());
+        assert_eq!(token_type_name(TokenType::KeywordControl as u32), "keywordcontrol");
+        assert_eq!(token_type_code("Comment"), TokenType::Comment as i32);
+        assert_eq!(token_type_name(TokenType::ALL.len() as u32), "");
+        assert_eq!(token_type_code("nonsense"), -1);
+    }
+    
+    #[test]
+    fn test_attribute_keywords() {
+        assert_eq!(significant_types("DCL P ENTRY(FIXED BYVALUE);")[3..6], [
+            ("(".to_string(), TokenType::Punctuation),
+            ("FIXED".to_string(), TokenType::KeywordType),
+            ("BYVALUE".to_string(), TokenType::Keyword),
+        ]);
+        // One keyword, not NOT + VARYING or NO + N + VARYING
+        assert_eq!(significant_types("DCL S CHAR(10) NONVARYING;")[6], ("NONVARYING".to_string(), TokenType::Keyword));
+        for word in ["nomap", "NoInit", "NONCONNECTED", "BYADDR", "ASSIGNABLE", "NONASSIGNABLE"] {
+            assert_eq!(classify_word(word), TokenType::Keyword, "{}", word);
+        }
+    }
+    
+    #[test]
+    fn test_preprocessor() {
         let code = "%INCLUDE MYFILE;";
         let tokens = tokenize(code);
         
         assert_eq!(tokens[0].token_type, TokenType::Preprocessor);
     }
+    
+    #[test]
+    fn test_percent_statements() {
+        for code in ["%PAGE;", "%skip(3);", "%GOTO L1;", "%NoPrint;", "%XINCLUDE SYSLIB;"] {
+            let tokens = tokenize(code);
+            assert_eq!(tokens[0].token_type, TokenType::Preprocessor, "{}", code);
+        }
+        
+        // Comments on a percent line are not swallowed by the directive
+        let tokens = tokenize("%INCLUDE MYFILE; /* copybook */");
+        assert_eq!(tokens[0].text, "%INCLUDE");
+        assert_eq!(tokens.last().unwrap().token_type, TokenType::Comment);
+        assert_eq!(tokens.last().unwrap().text, "/* copybook */");
+    }
 }
@@ -6,7 +6,7 @@ import init, { tokenize_flat, version } from '../pli-lexer-wasm/pkg/pli_lexer_wa
 // Token interfész (kompatibilis a régi highlightPLI-vel)
 export interface HighlightToken {
   text: string;
-  type: 'keyword' | 'string' | 'comment' | 'number' | 'operator' | 'preprocessor' | 'builtin' | 'identifier' | 'punctuation' | 'whitespace' | 'newline' | 'normal';
+  type: 'keyword' | 'string' | 'comment' | 'number' | 'operator' | 'preprocessor' | 'builtin' | 'function' | 'identifier' | 'punctuation' | 'whitespace' | 'newline' | 'normal';
 }
 
 // Token type mapping (Rust enum -> CSS class name)
@@ -23,6 +23,20 @@ const TOKEN_TYPE_MAP: Record<number, HighlightToken['type']> = {
   9: 'normal',       // TokenType::Whitespace
   10: 'normal',      // TokenType::Newline
   11: 'normal',      // TokenType::Unknown
+  12: 'keyword',     // TokenType::KeywordControl
+  13: 'keyword',     // TokenType::KeywordType
+  14: 'keyword',     // TokenType::KeywordStorage
+  15: 'keyword',     // TokenType::KeywordIo
+  16: 'function',    // TokenType::Label (named entry points and statements)
+  17: 'normal',      // TokenType::SequenceNumber
+  18: 'string',      // TokenType::Picture
+  19: 'preprocessor',// TokenType::Directive
+  20: 'keyword',     // TokenType::Condition
+  21: 'string',      // TokenType::StringDouble
+  22: 'normal',      // TokenType::Eof
+  23: 'builtin',     // TokenType::Pseudovariable
+  24: 'keyword',     // TokenType::FormatItem
+  25: 'string',      // TokenType::GraphicString
 };
 
 // WASM inicializálás állapota